@@ -0,0 +1,267 @@
+// Big decimal numbers of "N precision" (cargo feature "bigdecimal").
+// The results are rounded to N significant digits, the series are calculated
+// in fixed point, with GUARD extra digits.
+use crate::error::CalcError;
+use crate::instructions::{Instruction, StackType};
+use bigdecimal::num_bigint::BigInt;
+use bigdecimal::{BigDecimal, Context, One, RoundingMode, Signed, ToPrimitive, Zero};
+
+const GUARD: u64 = 10;
+const MAX_EXP: i64 = 23_100; // limit of expe, e^23026 is about 1e10000
+
+// Round to prec significant digits
+pub fn round(x: BigDecimal, prec: u64) -> BigDecimal {
+    if x.digits() > prec {
+        x.with_prec(prec).normalized()
+    } else {
+        x
+    }
+}
+
+// The number as big decimal, a real number is converted from its shortest form (0.1 is 0.1)
+pub fn from(a: &StackType) -> Option<BigDecimal> {
+    match a {
+        StackType::Big(a) => Some(a.clone()),
+        StackType::Int(a) => Some(BigDecimal::from(*a)),
+        StackType::Rational(a) => {
+            Some(BigDecimal::from(a.numer().clone()) / BigDecimal::from(a.denom().clone()))
+        }
+        StackType::Double(a) => format!("{a:e}").parse().ok(),
+        _ => None,
+    }
+}
+
+fn context(prec: u64) -> Context {
+    Context::default().with_prec(prec).unwrap_or_default()
+}
+
+// 10^-s, the last digit in fixed point
+fn eps(s: i64) -> BigDecimal {
+    BigDecimal::new(BigInt::one(), s)
+}
+
+// Round to s fractional digits
+fn fix(x: BigDecimal, s: i64) -> BigDecimal {
+    x.with_scale_round(s, RoundingMode::HalfEven)
+}
+
+// a / b with s fractional digits, truncated (the / of BigDecimal stops at 100 digits)
+fn div(a: &BigDecimal, b: &BigDecimal, s: i64) -> BigDecimal {
+    let (ai, ae) = a.as_bigint_and_exponent();
+    let (bi, be) = b.as_bigint_and_exponent();
+    // a / b = ai / bi * 10^(be - ae)
+    let shift = s + be - ae;
+    let ten = BigInt::from(10);
+    let q = if shift >= 0 {
+        ai * ten.pow(shift as u32) / bi
+    } else {
+        ai / (bi * ten.pow(-shift as u32))
+    };
+    BigDecimal::new(q, s)
+}
+
+fn sqrt(x: &BigDecimal, s: i64) -> BigDecimal {
+    let prec = s + x.order_of_magnitude().max(0) + 2;
+    x.sqrt_with_context(&context(prec as u64))
+        .unwrap_or_default()
+}
+
+// x - x^3/3 + x^5/5 ..., for small x
+fn atan_series(x: &BigDecimal, s: i64) -> BigDecimal {
+    let x2 = fix(x * x, s);
+    let mut power = x.clone();
+    let mut sum = x.clone();
+    let mut n = 1;
+    while power.abs() >= eps(s) {
+        power = fix(-(power * &x2), s);
+        n += 2;
+        sum += div(&power, &BigDecimal::from(n), s);
+    }
+    sum
+}
+
+// atan(1/n) = 1/n - 1/(3 n^3) + 1/(5 n^5) ..., the powers are divided by n^2
+fn atan_inv(n: u64, s: i64) -> BigDecimal {
+    let n2 = BigDecimal::from(n * n);
+    let mut power = div(&BigDecimal::one(), &BigDecimal::from(n), s);
+    let mut sum = power.clone();
+    let mut k = 1;
+    while !power.is_zero() {
+        power = div(&power, &n2, s);
+        k += 2;
+        let term = div(&power, &BigDecimal::from(k), s);
+        if k % 4 == 1 {
+            sum += term;
+        } else {
+            sum -= term;
+        }
+    }
+    sum
+}
+
+// pi = 16 atan(1/5) - 4 atan(1/239), Machin
+fn pi(s: i64) -> BigDecimal {
+    let a = atan_inv(5, s + 2);
+    let b = atan_inv(239, s + 2);
+    fix(a * BigDecimal::from(16) - b * BigDecimal::from(4), s)
+}
+
+// atan(x) = 2 atan(x / (1 + sqrt(1 + x^2))), until x is small for the series
+fn atan(x: &BigDecimal, s: i64) -> BigDecimal {
+    let s = s + 2;
+    let limit = BigDecimal::new(BigInt::one(), 1);
+    let mut x = x.clone();
+    let mut factor = BigDecimal::one();
+    while x.abs() > limit {
+        let r = sqrt(&(BigDecimal::one() + &x * &x), s);
+        x = div(&x, &(BigDecimal::one() + r), s);
+        factor *= BigDecimal::from(2);
+    }
+    fix(atan_series(&x, s) * factor, s - 2)
+}
+
+fn asin(x: &BigDecimal, s: i64) -> Result<BigDecimal, CalcError> {
+    let one = BigDecimal::one();
+    match x.abs() {
+        a if a > one => Err(CalcError::BadArgument),
+        a if a == one => Ok(div(
+            &pi(s),
+            &BigDecimal::from(2 * x.signum().to_i64().unwrap_or(1)),
+            s,
+        )),
+        _ => Ok(atan(&div(x, &sqrt(&(one - x * x), s + 2), s + 2), s)),
+    }
+}
+
+fn acos(x: &BigDecimal, s: i64) -> Result<BigDecimal, CalcError> {
+    Ok(div(&pi(s), &BigDecimal::from(2), s) - asin(x, s)?)
+}
+
+// Taylor series, term(k) = -term(k-1) x^2 / (n (n + 1)), n = n0, n0 + 2 ...
+fn alternating(first: BigDecimal, x2: &BigDecimal, mut n: u64, s: i64) -> BigDecimal {
+    let mut term = first.clone();
+    let mut sum = first;
+    while term.abs() >= eps(s) {
+        term = div(&-(term * x2), &BigDecimal::from(n * (n + 1)), s);
+        sum += &term;
+        n += 2;
+    }
+    sum
+}
+
+// x - k 2pi, between -2pi and 2pi
+fn reduce(x: &BigDecimal, s: i64) -> BigDecimal {
+    let s2 = s + x.order_of_magnitude().max(0) + 2;
+    let two_pi = pi(s2) * BigDecimal::from(2);
+    let k = div(x, &two_pi, 0);
+    fix(x - k * two_pi, s)
+}
+
+fn sin(x: &BigDecimal, s: i64) -> BigDecimal {
+    let x = reduce(x, s + 2);
+    let x2 = fix(&x * &x, s + 2);
+    fix(alternating(x, &x2, 2, s + 2), s)
+}
+
+fn cos(x: &BigDecimal, s: i64) -> BigDecimal {
+    let x = reduce(x, s + 2);
+    let x2 = fix(&x * &x, s + 2);
+    fix(alternating(BigDecimal::one(), &x2, 1, s + 2), s)
+}
+
+fn tan(x: &BigDecimal, s: i64) -> Result<BigDecimal, CalcError> {
+    let c = cos(x, s + 2);
+    if c.is_zero() {
+        return Err(CalcError::DivisionByZero);
+    }
+    Ok(div(&sin(x, s + 2), &c, s))
+}
+
+fn exp(x: &BigDecimal, prec: u64) -> Result<BigDecimal, CalcError> {
+    if x.abs() > MAX_EXP {
+        return Err(CalcError::OutOfRange);
+    }
+    Ok(x.exp_with_context(&context(prec)))
+}
+
+// Halley's method, y += 2 (x - e^y) / (x + e^y), from the f64 logarithm
+fn ln(x: &BigDecimal, s: i64) -> BigDecimal {
+    let (m, e) = x.with_prec(17).as_bigint_and_exponent();
+    let y0 = m.to_f64().unwrap_or(1.0).ln() - e as f64 * std::f64::consts::LN_10;
+    let mut y: BigDecimal = format!("{y0:e}").parse().unwrap_or_default();
+    let prec = (s + y.order_of_magnitude().max(0) + 2) as u64;
+    for _ in 0..100 {
+        let ey = y.exp_with_context(&context(prec));
+        let delta = div(&((x - &ey) * BigDecimal::from(2)), &(x + &ey), s);
+        y += &delta;
+        if delta.abs() < eps(s - 2) {
+            break;
+        }
+    }
+    fix(y, s)
+}
+
+// The instructions with one big decimal number, None for the other instructions
+pub fn unary(
+    instr: &Instruction,
+    a: &BigDecimal,
+    prec: u64,
+) -> Option<Result<BigDecimal, CalcError>> {
+    // fractional digits, more for the small numbers
+    let s = (prec + GUARD) as i64 + (-a.order_of_magnitude()).max(0);
+    let degree = || div(&pi(s + 2), &BigDecimal::from(180), s + 2);
+    let res = match instr {
+        Instruction::Abs => Ok(a.abs()),
+        Instruction::Floor => Ok(a.with_scale_round(0, RoundingMode::Floor)),
+        Instruction::Ceil => Ok(a.with_scale_round(0, RoundingMode::Ceiling)),
+        Instruction::Round => Ok(a.with_scale_round(0, RoundingMode::HalfUp)),
+        Instruction::Trunc => Ok(a.with_scale_round(0, RoundingMode::Down)),
+        Instruction::Sqrt if a.is_negative() => Err(CalcError::BadArgument),
+        Instruction::Sqrt => Ok(sqrt(a, s)),
+        Instruction::Loge if !a.is_positive() => Err(CalcError::BadArgument),
+        Instruction::Loge => Ok(ln(a, s)),
+        Instruction::Expe => exp(a, prec + GUARD),
+        Instruction::SinR => Ok(sin(a, s)),
+        Instruction::CosR => Ok(cos(a, s)),
+        Instruction::TanR => tan(a, s),
+        Instruction::SinD => Ok(sin(&(a * degree()), s)),
+        Instruction::CosD => Ok(cos(&(a * degree()), s)),
+        Instruction::TanD => tan(&(a * degree()), s),
+        Instruction::AsinR => asin(a, s),
+        Instruction::AcosR => acos(a, s),
+        Instruction::AtanR => Ok(atan(a, s)),
+        Instruction::AsinD => asin(a, s + 2).map(|r| div(&r, &degree(), s)),
+        Instruction::AcosD => acos(a, s + 2).map(|r| div(&r, &degree(), s)),
+        Instruction::AtanD => Ok(div(&atan(a, s + 2), &degree(), s)),
+        _ => return None,
+    };
+    Some(res.map(|r| round(r, prec)))
+}
+
+// + - * / and the relations of two big decimal numbers, b is the second: b - a.
+// None for the other instructions.
+pub fn binary(
+    instr: &Instruction,
+    b: &BigDecimal,
+    a: &BigDecimal,
+    prec: u64,
+) -> Option<Result<StackType, CalcError>> {
+    let flag = |c: bool| Ok(StackType::Double(c as i32 as f64));
+    let res = match instr {
+        Instruction::Add => Ok(StackType::Big(round(b + a, prec))),
+        Instruction::Sub => Ok(StackType::Big(round(b - a, prec))),
+        Instruction::Mul => Ok(StackType::Big(round(b * a, prec))),
+        Instruction::Div if a.is_zero() => Err(CalcError::DivisionByZero),
+        Instruction::Div => {
+            let s = (prec + GUARD) as i64 - b.order_of_magnitude() + a.order_of_magnitude();
+            Ok(StackType::Big(round(div(b, a, s), prec)))
+        }
+        Instruction::Gt => flag(b > a),
+        Instruction::Lt => flag(b < a),
+        Instruction::Ge => flag(b >= a),
+        Instruction::Le => flag(b <= a),
+        Instruction::Eq => flag(b == a),
+        _ => return None,
+    };
+    Some(res)
+}
@@ -0,0 +1,47 @@
+use crate::error::Error;
+use crate::instructions::StackType;
+use crate::parser::Parser;
+
+/// Embeddable calculator: evaluates RPN source and returns the stack.
+///
+/// ```
+/// use rpncalc::{CalcError, Calculator, StackType};
+///
+/// let mut calc = Calculator::new();
+/// assert_eq!(calc.eval("10 6 4 - /").unwrap(), vec![StackType::Double(5.0)]);
+/// assert_eq!(calc.eval("drop drop").unwrap_err().kind, CalcError::StackUnderflow);
+/// ```
+pub struct Calculator {
+    parser: Parser,
+}
+
+impl Calculator {
+    pub fn new() -> Self {
+        Calculator {
+            parser: Parser::new(false),
+        }
+    }
+
+    // The stack, registers, vectors and subroutines are kept between the calls.
+    pub fn eval(&mut self, source: &str) -> Result<Vec<StackType>, Error> {
+        for line in source.lines() {
+            self.parser.parse_line(line)?;
+        }
+        self.parser.flush()?;
+        Ok(self.parser.stack().to_vec())
+    }
+
+    pub fn stack(&self) -> &[StackType] {
+        self.parser.stack()
+    }
+
+    pub fn parser(&mut self) -> &mut Parser {
+        &mut self.parser
+    }
+}
+
+impl Default for Calculator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
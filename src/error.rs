@@ -0,0 +1,191 @@
+use crate::instructions::{Instruction, StackType};
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalcError {
+    StackUnderflow,
+    StackOverflow,
+    ReturnStackUnderflow,
+    ReturnStackOverflow,
+    NoLoop, // "i", "j" or "loop" outside of a counted loop
+    UnsetDeferred(String),
+    TypeMismatch {
+        expected: &'static str,
+        found: Box<StackType>,
+    },
+    // parser errors, with the token
+    UnknownWord(String),
+    BadNumber(String),
+    BadString(String), // without the closing '"'
+    FileNotFound(String),
+    IncludeTooDeep(String),
+    BadRegister(String), // the word without a valid register/vector number
+    BadVariable(String), // variable without @ or !, or @ and ! without variable
+    BadDefer(String),    // not "' SUBROUTINE is DEFERRED"
+    BadName(String),     // a number as the name of a subroutine, variable, constant or local
+    IndexOutOfRange {
+        vector: u8,
+        index: f64,
+        len: usize,
+    },
+    InvalidLength(f64),
+    BadWordSize(f64),   // not 8, 16, 32 or 64
+    Unbalanced(String), // "]" without "[", ";" without ":", ...
+    DivisionByZero,
+    IntegerOverflow,
+    OutOfRange,  // the result is too big
+    BadArgument, // outside of the domain, e.g. sqrt of a negative big decimal
+    Throw(i64),  // "throw" without "catch"
+    Interrupted,
+}
+
+impl CalcError {
+    // The error code for "catch", FORTH-like
+    pub fn code(&self) -> i64 {
+        match self {
+            CalcError::StackOverflow => -3,
+            CalcError::StackUnderflow => -4,
+            CalcError::ReturnStackOverflow => -5,
+            CalcError::ReturnStackUnderflow => -6,
+            CalcError::IndexOutOfRange { .. } | CalcError::InvalidLength(_) => -9,
+            CalcError::DivisionByZero => -10,
+            CalcError::IntegerOverflow => -11,
+            CalcError::OutOfRange => -43,
+            CalcError::BadArgument => -46,
+            CalcError::TypeMismatch { .. } => -12,
+            CalcError::UnknownWord(_)
+            | CalcError::BadVariable(_)
+            | CalcError::BadDefer(_)
+            | CalcError::BadName(_) => -13,
+            CalcError::Unbalanced(_) => -22,
+            CalcError::BadNumber(_)
+            | CalcError::BadString(_)
+            | CalcError::BadRegister(_)
+            | CalcError::BadWordSize(_) => -24,
+            CalcError::NoLoop => -26,
+            CalcError::IncludeTooDeep(_) => -37,
+            CalcError::FileNotFound(_) => -38,
+            CalcError::Interrupted => -28,
+            CalcError::UnsetDeferred(_) => -256,
+            CalcError::Throw(n) => *n,
+        }
+    }
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalcError::StackUnderflow => write!(f, "stack is empty"),
+            CalcError::StackOverflow => write!(f, "stack is full, please clear it"),
+            CalcError::ReturnStackUnderflow => write!(f, "return stack is empty"),
+            CalcError::ReturnStackOverflow => {
+                write!(f, "return stack is full (too deep recursion)")
+            }
+            CalcError::NoLoop => write!(f, "not inside of a counted loop"),
+            CalcError::UnsetDeferred(name) => {
+                write!(
+                    f,
+                    "deferred word '{name}' is not set, use ' SUBROUTINE is {name}"
+                )
+            }
+            CalcError::TypeMismatch { expected, found } => {
+                write!(f, "type mismatch: expected {expected}, found {found:?}")
+            }
+            CalcError::UnknownWord(_) => {
+                write!(f, "not a number, invalid command. Please type 'help'.")
+            }
+            CalcError::BadNumber(_) => write!(f, "number error"),
+            CalcError::BadString(_) => write!(f, "unterminated string"),
+            CalcError::FileNotFound(_) => {
+                write!(f, "file not found, the search path is RPNCALC_PATH")
+            }
+            CalcError::IncludeTooDeep(_) => write!(f, "too deep nesting of include"),
+            CalcError::BadRegister(_) => {
+                write!(
+                    f,
+                    "register number (0 ... 255) needed before this instruction"
+                )
+            }
+            CalcError::BadVariable(_) => write!(f, "variable name with @ or ! needed"),
+            CalcError::BadDefer(_) => write!(f, "' SUBROUTINE is DEFERRED needed"),
+            CalcError::BadName(_) => write!(f, "a number can't be a name"),
+            CalcError::IndexOutOfRange { vector, index, len } => {
+                write!(
+                    f,
+                    "index {index} out of range for vector {vector} (len {len})"
+                )
+            }
+            CalcError::InvalidLength(len) => write!(f, "invalid vector length: {len}"),
+            CalcError::BadWordSize(n) => write!(f, "invalid word size {n}, use 8, 16, 32 or 64"),
+            CalcError::Unbalanced(_) => {
+                write!(f, "unbalanced control structure or subroutine definition")
+            }
+            CalcError::DivisionByZero => write!(f, "division by zero"),
+            CalcError::IntegerOverflow => write!(f, "integer overflow"),
+            CalcError::OutOfRange => write!(f, "result out of range"),
+            CalcError::BadArgument => write!(f, "invalid argument"),
+            CalcError::Throw(n) => write!(f, "uncaught exception {n}"),
+            CalcError::Interrupted => write!(f, "Ctrl-C ... stop"),
+        }
+    }
+}
+
+// Source position of a token, for the diagnostics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrcPos {
+    pub file: Arc<str>,
+    pub line: usize,
+    pub col: usize,
+    pub token: String,
+}
+
+impl fmt::Display for SrcPos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{} '{}'",
+            self.file, self.line, self.col, self.token
+        )
+    }
+}
+
+// CalcError with the place of the failure; runtime errors know the instruction and the PC,
+// and the call trace through the subroutines (innermost call first).
+// The large parts are boxed, so the Results stay small.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub kind: CalcError,
+    pub instr: Option<Box<Instruction>>,
+    pub pc: Option<usize>,
+    pub pos: Option<Box<SrcPos>>,
+    pub trace: Vec<SrcPos>,
+}
+
+impl From<CalcError> for Error {
+    fn from(kind: CalcError) -> Self {
+        Error {
+            kind,
+            instr: None,
+            pc: None,
+            pos: None,
+            trace: vec![],
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.pos, &self.instr, self.pc) {
+            (Some(pos), _, _) => write!(f, "{pos}: {}", self.kind)?,
+            (None, Some(instr), Some(pc)) => write!(f, "PC {pc} {instr:?}: {}", self.kind)?,
+            _ => write!(f, "{}", self.kind)?,
+        }
+        for pos in &self.trace {
+            write!(f, "\n    called from {pos}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,19 +1,49 @@
+#[cfg(feature = "bigdecimal")]
+use bigdecimal::BigDecimal;
 use num_complex::Complex;
+use num_rational::BigRational;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum StackType {
     Double(f64),
+    Int(i64),
+    Rational(BigRational), // "exactmode", normalized, also the integers beyond 64 bits
+    #[cfg(feature = "bigdecimal")]
+    Big(BigDecimal), // "N precision", then the literals are big decimals
     Complex(Complex<f64>),
-    //Str(&str),
+    Str(String),
+    Quote(usize), // "{ ... }", address of the code
     None,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Literal(StackType),
-    Call(usize), // ":"
-    Ret,         // ";"
-    Jnz(usize),  // "]", jump back
+    Call(usize),     // ":"
+    Ret,             // ";"
+    Locals(usize),   // "{: a b :}", pop the locals of the subroutine
+    EndLocals,       // ";" of a subroutine with locals
+    LocalGet(usize), // local name
+    Jnz(usize),      // "]", jump back
+    Jz(usize),       // "if", "while", "until", jump if false
+    Jmp(usize),      // "else", "repeat"
+    Do,              // "do"
+    Loop(usize),     // "loop", jump back to the start of the body
+    PlusLoop(usize), // "+loop"
+    I,               // "i", index of the innermost counted loop
+    J,               // "j", index of the outer counted loop
+
+    CallDeferred(usize),       // deferred word, "defer NAME"
+    SetDeferred(usize, usize), // "' SUBROUTINE is NAME"
+
+    // Quotations, "{ ... }" is a literal, jump over the code
+    CallQuote, // "call"
+    Times,     // "times", start
+    TimesStep, // after "times", call the quotation again
+    Ifte,      // "ifte"
+    Catch,     // "catch", call the quotation
+    EndCatch,  // after "catch", push 0
+    Throw,     // "throw"
 
     Dup,       // "dup"
     Drop,      // "drop"
@@ -34,10 +64,31 @@ pub enum Instruction {
     Shl, // "shl"
     Shr, // "shr"
 
+    // Integers
+    Rol,      // "rol", rotate left
+    Ror,      // "ror", rotate right
+    Popcount, // "popcount"
+    Clz,      // "clz", leading zeros
+    ToInt,    // ">int", truncation
+    ToFloat,  // ">float"
+
+    // Rationals
+    Exact(bool), // "exactmode": the integer division is rational, or "intmode" | "floatmode"
+    ToFrac,      // ">frac", continued fraction of a real number
+
     Abs,   // "abs"
     Floor, // "floor"
     Ceil,  // "ceil"
     Round, // "round"
+    Sqrt,  // "sqrt"
+    Cbrt,  // "cbrt"
+    Trunc, // "trunc"
+    Sign,  // "sign", -1, 0 or 1, a / abs(a) for complex
+    Fmod,  // "fmod", remainder of the truncated division
+    Hypot, // "hypot"
+    Atan2, // "atan2", y x atan2
+    Min,   // "min"
+    Max,   // "max"
     CosR,  // "cosr"
     SinR,  // "sinr"
     TanR,  // "tanr"
@@ -50,23 +101,59 @@ pub enum Instruction {
     AcosD, // "acosd"
     AsinD, // "asind"
     AtanD, // "atand"
-    Loge,  // "loge"
-    Log2,  // "log2"
-    Log10, // "log10"
-    Logx,  // "logx"
-    Expe,  // "expe"
-    Exp2,  // "exp2"
-    Exp10, // "exp10"
-    Expx,  // "expx"
-    Gt,    // ">"
-    Lt,    // "<"
-    Ge,    // ">="
-    Le,    // "<="
-    Eq,    // "="
+    Sinh,  // "sinh"
+    Cosh,  // "cosh"
+    Tanh,  // "tanh"
+    Asinh, // "asinh"
+    Acosh, // "acosh"
+    Atanh, // "atanh"
+
+    // Special functions
+    Gamma,     // "gamma"
+    Lgamma,    // "lgamma", ln |gamma|
+    Erf,       // "erf"
+    Erfc,      // "erfc", 1 - erf
+    BesselJ0,  // "j0"
+    BesselJ1,  // "j1"
+    BesselJn,  // "jn", N X jn
+    BesselI0,  // "i0", modified Bessel function
+    Factorial, // "n!"
+    Ncr,       // "ncr" | "nCr", N R ncr, combinations
+    Npr,       // "npr" | "nPr", permutations
+
+    // Number theory, 64 bit integers
+    Gcd,         // "gcd"
+    Lcm,         // "lcm"
+    Mod,         // "mod" | "%", floored, the sign of the divisor
+    ModPow,      // "modpow", B E M modpow
+    ModInv,      // "modinv", A M modinv
+    IsPrime,     // "isprime"
+    NextPrime,   // "nextprime", the next greater prime
+    Factor,      // "factor", the prime factors to the stack
+    VFactor(u8), // VNUM + "vfactor", the prime factors to the vector, and their count
+    Loge,        // "loge"
+    Log2,        // "log2"
+    Log10,       // "log10"
+    Logx,        // "logx"
+    Expe,        // "expe"
+    Exp2,        // "exp2"
+    Exp10,       // "exp10"
+    Expx,        // "expx"
+    Gt,          // ">"
+    Lt,          // "<"
+    Ge,          // ">="
+    Le,          // "<="
+    Eq,          // "="
 
     Real, // "real"
     Imag, // "imag"
     R2c,  // "r2c"
+    P2c,  // "p2c", magnitude and phase to complex
+    C2p,  // "c2p", complex to magnitude and phase
+    Arg,  // "arg", phase
+    Conj, // "conj", conjugate
+
+    CplxMode(bool), // "cplxmode" | "realmode", complex result outside of the real domain
 
     // Registers
     Save(u8), // RNUM + "save"
@@ -75,6 +162,10 @@ pub enum Instruction {
     Clregs,   // "clregs"
     DumpReg,  // "dumpreg" | "dr"
 
+    // Named variables and constants
+    Store(usize), // NAME + "!", VALUE + "constant" + NAME
+    Fetch(usize), // NAME + "@", constant NAME
+
     // Vectors
     Vreal(u8), // VNUM + "vreal"
     Vcplx(u8), // VNUM + "vcplx"
@@ -85,20 +176,55 @@ pub enum Instruction {
     DumpVec,   // "dumpvec" | "dv"
 
     // Print
-    FractionalDigit, // "frdigit" | "precision" => {
+    FractionalDigit, // "frdigit"
+    #[cfg(feature = "bigdecimal")]
+    Precision(u64), // "N precision", significant digits of the big decimals
     Print,           // "p" | "print"
+    Radix(u32),      // "hex" | "dec" | "oct" | "bin"
+    WordSize,        // "wordsize", 8, 16, 32 or 64 bits
+    Signed(bool),    // "signed" | "unsigned"
+
+    Polar(bool),   // "polar" | "rect", output of the complex numbers
+    Degrees(bool), // "degrees" | "radians", phase of the polar form
+
+    // Strings, "text" is a literal
+    Type,  // "type", ."text" is "text" type
+    Cr,    // "cr"
+    Len,   // "len"
+    ToStr, // ">str"
+    StrTo, // "str>"
+
     // Help,      // help() called in parser,
     Quit, // "quit" | "bye" | "exit" | "q"
 }
 
+impl Instruction {
+    // The code address in the instruction, for the relocation
+    pub fn addr_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Instruction::Call(addr)
+            | Instruction::SetDeferred(_, addr)
+            | Instruction::Literal(StackType::Quote(addr))
+            | Instruction::Jnz(addr)
+            | Instruction::Jz(addr)
+            | Instruction::Jmp(addr)
+            | Instruction::Loop(addr)
+            | Instruction::PlusLoop(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
 pub fn help() {
     println!("RPN complex calculator, inspired by the FORTH, gforth and dc commands.");
-    println!("Cmdline args: -q or --quiet, -f <filename> or --file <filename>, and -h or --help");
+    println!("Cmdline args: -q or --quiet, -f <filename> or --file <filename>, -d <depth> or --depth <depth>, and -h or --help");
     println!();
     println!("   Basic example:      10 6 4 - / p                     # p as print, 6 - 4 --> 2    10 / 2 = 5");
     println!();
     println!("   Stack operation:    dup drop over rot swap clear");
     println!("   Stack <--> Reg:     RNUM save load creg              # registernumber is 8 bit");
+    println!("   Named variable:     variable rate  0.5 rate !  rate @  # store and fetch");
+    println!("   Named constant:     42 constant answer  answer p     # dumpreg lists them");
     println!("   Stack <--> Vector:  VNUM vsave vload cvec            # VNUM is 8 bit");
     println!("   Create a vector:    LEN VNUM vreal or vcplx          # VNUM is 8 bit");
     println!();
@@ -106,27 +232,80 @@ pub fn help() {
     println!("   Debug:              dumpstack(ds), dumpreg(dr), dumpvec(dv)");
     println!();
     println!("   Literal:            3 4j                             # real or complex number");
-    println!("   Arithmetic:         + - * / abs");
-    println!("   Rounding:           floor ceil round");
+    println!("   Arithmetic:         + - * / abs sqrt cbrt fmod sign min max hypot");
+    println!("   Rounding:           floor ceil round trunc");
     println!("   Complex:            real imag r2c");
-    println!("   Logical:            and or xor neg, N shl N shr");
+    println!("   Complex domain:     cplxmode -1 loge p realmode      # i*pi, not NaN; the functions take complex numbers");
+    println!("   Polar:              1.5@30d 2@0.5, mag phase p2c     # degrees or radians, c2p arg conj");
+    println!("   Polar output:       polar rect, degrees radians      # 1.5@30d, also the unit of p2c c2p arg");
+    println!("   Integer:            intmode 7 2 / p floatmode        # 3, exact 64 bit integers, >int >float");
+    println!("   Rational:           exactmode 1 3 / 3 * p            # 1, 1/3 is exact, 0.1 >frac, >float");
+    println!("   Logical (64 bit):   and or xor neg, N shl N shr, N rol N ror, popcount clz");
+    println!("   Radix literal:      0x1F 0o17 0b1010_0101            # integers, '_' separates the digits");
+    println!("   Programmer mode:    hex dec oct bin, 16 wordsize     # output and bit operations, signed unsigned");
     println!();
-    println!("   Trigonometric(rad): sinr, cosr, tanr, asinr, acosr, atanr");
+    println!("   Trigonometric(rad): sinr, cosr, tanr, asinr, acosr, atanr, y x atan2");
     println!("   Trigonometric(deg): sind, cosd, tand, asind, acosd, atand");
-    println!("   Logarithm:          loge expe log10 exp10 log2 exp2 logx expx");
+    println!("   Hyperbolic:         sinh cosh tanh asinh acosh atanh");
+    println!("   Special functions:  gamma lgamma erf erfc j0 j1 i0   # N X jn, Bessel functions");
+    println!(
+        "   Combinatorics:      5 n! p 52 5 ncr p 10 3 npr       # exact for integers (intmode)"
+    );
+    println!("   Number theory:      12 18 gcd lcm, -7 3 mod p        # 2, the sign of the divisor, % is mod");
+    println!(
+        "   Modular:            2 100 7 modpow, 3 7 modinv       # B^E mod M, 3 * 5 = 1 mod 7"
+    );
+    println!(
+        "   Primes:             97 isprime, 100 nextprime        # 360 factor, N VNUM vfactor"
+    );
+    println!("   Logarithm:          loge expe log10 exp10 log2 exp2 logx expx(pow)");
     println!();
     println!("   Output:             print or p                       # stack is unchanged!");
     println!(
         "   Output frac. digit: 4 frdigit                        # N.xxxx, 0 auto, max 17 (K)"
     );
+    println!(
+        "   Big decimal:        100 precision 2 sqrt p           # N digits, 0 precision: off (feature)"
+    );
+    println!();
+    println!(
+        "   String:             \"U = \" 230 >str + type cr        # + concatenation, type prints"
+    );
+    println!(
+        "   String words:       .\"text\" cr len >str str>         # = < > compare two strings"
+    );
     println!();
     println!("   Subroutine:         : srname 10 4 p drop ;           # multiline is allowed.");
     println!("   Call subroutine:    srname                           # as a normal command label");
-    println!("   List subroutines:   dumpsr(dsr)                      # print first line");
+    println!(
+        "   Local variables:    : hyp {{: a b :}} a a * b b * + 0.5 expx ;  # a, b from the stack"
+    );
+    println!("   Recursion:          : fact dup 1 > if dup 1 - recurse * then ;  # or by its name");
+    println!("   Forward reference:  defer f  : g f ;  : h 2 * ;  ' h is f  3 g p");
+    println!("   Forget:             forget srname                    # and the later subroutines");
+    println!("   Marker:             marker m  : a 1 ;  m             # m forgets a and itself");
+    println!("   Quotation:          5 {{ 2 * }} call p                 # code block on the stack");
+    println!(
+        "   Combinators:        0 3 {{ 1 + }} times, 1 {{ 2 }} {{ 3 }} ifte  # N times, if-else"
+    );
+    println!("   Exception:          {{ 1 0 / }} catch p                # error code (-10) or 0");
+    println!(
+        "   Throw:              {{ 42 throw }} catch p             # 42, 0 throw does nothing"
+    );
+    println!("   Include a file:     include lib.rpn, require lib.rpn # require loads once, RPNCALC_PATH");
+    println!(
+        "   Vocabulary:         vocab dsp : fir ... ; end-vocab  # dsp::fir, or using dsp  fir"
+    );
+    println!("   List subroutines:   dumpsr(dsr)                      # print first line, per vocabulary");
     println!();
     println!("   Relation:           5 4 > p                          # 1");
     println!("   Loop:               10 [ 1 - p dup ]                 # loop if not 0 before ']' and pop the result");
     println!("   Loop:               10 [ 1 - p dup 5 > ]             # loop if greater than 5");
+    println!("   Pre-test loop:      5 begin dup 0 > while 1 - p repeat");
+    println!("   Post-test loop:     5 begin 1 - p dup 0 = until      # loop until true");
+    println!("   Counted loop:       10 0 do i p drop loop            # i: 0 ... 9, j: index of the outer loop");
+    println!("   Counted loop:       0 10 do i p drop -2 +loop        # i: 10 8 ... 0, the step is on the stack");
+    println!("   Condition:          5 4 > if 1 else 2 then p         # 'else' part is optional");
     println!();
     println!("   Quit:               q quit bye exit");
     println!();
@@ -0,0 +1,15 @@
+#[cfg(feature = "bigdecimal")]
+mod bigmath;
+mod calculator;
+mod error;
+mod instructions;
+mod numtheory;
+mod parser;
+mod runner;
+mod special;
+
+pub use calculator::Calculator;
+pub use error::{CalcError, Error, SrcPos};
+pub use instructions::{help, Instruction, StackType};
+pub use parser::Parser;
+pub use runner::Runner;
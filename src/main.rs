@@ -1,25 +1,24 @@
 use getargs::{Opt, Options};
+use rpncalc::{help, Parser};
 use std::env::args;
 use std::fs::read_to_string;
 use std::io::{self, BufRead};
+use std::sync::atomic::Ordering;
 
-mod instructions;
-mod parser;
-mod runner;
-
-fn get_args() -> (Vec<String>, bool) {
+fn get_args() -> (Vec<String>, bool, Option<usize>) {
     let args = args().skip(1).collect::<Vec<_>>();
     if args.is_empty() {
-        instructions::help();
+        help();
     }
     let mut opts = Options::new(args.iter().map(String::as_str));
 
     let mut filenames = vec![];
     let mut verbose = false;
+    let mut depth = None;
     while let Some(opt) = opts.next_opt().expect("argument parsing error") {
         match opt {
             Opt::Short('h') | Opt::Long("help") => {
-                instructions::help();
+                help();
                 std::process::exit(0);
             }
 
@@ -34,6 +33,14 @@ fn get_args() -> (Vec<String>, bool) {
 
             Opt::Short('v') | Opt::Long("verbose") => verbose = true,
 
+            // max. depth of the subroutine calls
+            Opt::Short('d') | Opt::Long("depth") => {
+                let Some(d) = opts.value().ok().and_then(|d| d.parse().ok()) else {
+                    panic!("No depth number!");
+                };
+                depth = Some(d);
+            }
+
             _ => {
                 eprintln!("Unknown option: {:?}", opt);
                 std::process::exit(-1)
@@ -44,20 +51,49 @@ fn get_args() -> (Vec<String>, bool) {
     for arg in opts.positionals() {
         eprintln!("positional: {:?}", arg)
     }
-    (filenames, verbose)
+    (filenames, verbose, depth)
+}
+
+// on error the exit code of the calculator will be 1
+fn parse_line(p: &mut Parser, line: &str, failed: &mut bool) {
+    if let Err(e) = p.parse_line(line) {
+        eprintln!("Error: {e}");
+        *failed = true;
+    }
+    if p.quit_requested() {
+        eprintln!("Exit from calculator. Bye.");
+        std::process::exit(*failed as i32);
+    }
 }
 
 fn main() {
-    let (filenames, verbose) = get_args();
-    let mut p = parser::Parser::new(verbose);
+    let (filenames, verbose, depth) = get_args();
+    let mut p = Parser::new(verbose);
+    if let Some(depth) = depth {
+        p.set_max_depth(depth);
+    }
 
+    let stopped = p.stop_handle();
+    ctrlc::set_handler(move || {
+        stopped.store(true, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let mut failed = false;
     for fname in filenames {
+        p.set_source(&fname);
         for line in read_to_string(fname).unwrap().lines() {
-            p.parse_line(line);
+            parse_line(&mut p, line, &mut failed);
         }
     }
 
+    p.set_source("<stdin>");
     for line in io::stdin().lock().lines().map_while(Result::ok) {
-        p.parse_line(&line);
+        parse_line(&mut p, &line, &mut failed);
+    }
+    if let Err(e) = p.flush() {
+        eprintln!("Error: {e}");
+        failed = true;
     }
+    std::process::exit(failed as i32);
 }
@@ -0,0 +1,136 @@
+// Number theory on 64 bit integers, the products are calculated in 128 bits.
+use crate::error::CalcError;
+
+const WITNESSES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37]; // enough below 2^64
+
+pub fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// Floored modulo, the result has the sign of the divisor: -7 3 mod is 2
+pub fn floor_mod(b: i64, a: i64) -> i64 {
+    let r = b.wrapping_rem(a);
+    if r != 0 && (r < 0) != (a < 0) {
+        r + a
+    } else {
+        r
+    }
+}
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    (a as u128 * b as u128 % m as u128) as u64
+}
+
+// b^e mod m, m > 0
+pub fn pow_mod(b: u64, mut e: u64, m: u64) -> u64 {
+    let mut b = b % m;
+    let mut res = 1 % m;
+    while e > 0 {
+        if e & 1 == 1 {
+            res = mul_mod(res, b, m);
+        }
+        b = mul_mod(b, b, m);
+        e >>= 1;
+    }
+    res
+}
+
+// The inverse of a modulo m > 0, by the extended Euclidean algorithm
+pub fn inv_mod(a: i64, m: i64) -> Result<i64, CalcError> {
+    let (mut r0, mut r1) = (floor_mod(a, m) as i128, m as i128);
+    let (mut s0, mut s1) = (1i128, 0i128);
+    while r1 != 0 {
+        let q = r0 / r1;
+        (r0, r1) = (r1, r0 - q * r1);
+        (s0, s1) = (s1, s0 - q * s1);
+    }
+    if r0 != 1 {
+        // a and m are not coprime
+        return Err(CalcError::BadArgument);
+    }
+    Ok(s0.rem_euclid(m as i128) as i64)
+}
+
+// Deterministic Miller-Rabin test
+pub fn is_prime(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for p in WITNESSES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+    WITNESSES.iter().all(|&a| {
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            return true;
+        }
+        for _ in 1..s {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                return true;
+            }
+        }
+        false
+    })
+}
+
+// The smallest prime greater than n, None above the 64 bit primes
+pub fn next_prime(n: u64) -> Option<u64> {
+    let mut n = n.checked_add(1)?.max(2);
+    while !is_prime(n) {
+        n = n.checked_add(1)?;
+    }
+    Some(n)
+}
+
+// A nontrivial divisor of the odd composite n, Pollard's rho with Floyd's cycle detection
+fn pollard_rho(n: u64) -> u64 {
+    for c in 1.. {
+        let f = |x: u64| (mul_mod(x, x, n) + c) % n;
+        let (mut x, mut y, mut d) = (2, 2, 1);
+        while d == 1 {
+            x = f(x);
+            y = f(f(y));
+            d = gcd(x.abs_diff(y), n);
+        }
+        if d != n {
+            return d;
+        }
+    }
+    unreachable!()
+}
+
+// The prime factors in increasing order, 1 and 0 have none
+pub fn factor(n: u64) -> Vec<u64> {
+    let mut factors = vec![];
+    let mut n = n;
+    for p in [2, 3, 5] {
+        while n > 1 && n.is_multiple_of(p) {
+            factors.push(p);
+            n /= p;
+        }
+    }
+    let mut stack = vec![n];
+    while let Some(m) = stack.pop() {
+        if m <= 1 {
+            continue;
+        }
+        if is_prime(m) {
+            factors.push(m);
+        } else {
+            let d = pollard_rho(m);
+            stack.push(d);
+            stack.push(m / d);
+        }
+    }
+    factors.sort_unstable();
+    factors
+}
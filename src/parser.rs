@@ -1,16 +1,112 @@
+use crate::error::{CalcError, Error, SrcPos};
 use crate::instructions::{help, Instruction, StackType};
-use crate::runner::Runner;
+use crate::runner::{rational, real, Mark, Runner};
+#[cfg(feature = "bigdecimal")]
+use bigdecimal::BigDecimal;
+use num_bigint::BigInt;
 use num_complex::Complex;
-use std::collections::HashMap;
+use num_rational::BigRational;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{atomic::AtomicBool, Arc};
+
+const COMPACT_LIMIT: usize = 1 << 16; // program length for the first compaction
+#[cfg(feature = "bigdecimal")]
+const MAX_PRECISION: f64 = 100_000.0;
+const MAX_INCLUDE: usize = 64; // nesting of the included files
+pub(crate) const MAX_EXACT_EXP: u64 = 10_000; // exponent of the exact and big numbers, 1e10000
+
+// Open control structures, with the address of the loop start or the jump to patch
+enum Control {
+    Loop(usize),  // "["
+    If(usize),    // "if", address of the Jz
+    Else(usize),  // "else", address of the Jmp
+    Do(usize),    // "do", start of the loop body
+    Begin(usize), // "begin", start of the loop body
+    While(usize), // "while", address of the Jz
+    Quote(usize), // "{", address of the Jmp over the code
+}
+
+// Named storage, the index is the slot in the runner
+#[derive(Clone, Copy)]
+enum Name {
+    Variable(usize),
+    Constant(usize),
+    Deferred(usize),
+}
+
+// Entry of the subroutine dictionary, mark.prog is the address of the code
+struct Subroutine {
+    mark: Mark,                     // the dictionary before the definition, for forget
+    description: String,            // first line, for dumpsr
+    older: Option<Box<Subroutine>>, // the replaced definition, forget brings it back
+}
+
+impl Subroutine {
+    // This definition and the replaced ones, newest first
+    fn chain(&self) -> impl Iterator<Item = &Subroutine> {
+        std::iter::successors(Some(self), |s| s.older.as_deref())
+    }
+
+    // The newest definition made before the mark
+    fn before(self, mark: Mark) -> Option<Subroutine> {
+        let mut s = self;
+        while s.mark.prog >= mark.prog {
+            s = *s.older?;
+        }
+        Some(s)
+    }
+}
+
+// Words waiting for a name
+#[derive(Clone, Copy, PartialEq)]
+enum Pending {
+    Variable,
+    Constant,
+    Defer,
+    Forget,
+    Marker,
+    Tick,          // "'", name of a subroutine
+    Is(usize),     // "is", name of a deferred word, with the address of the subroutine
+    Include(bool), // "include" or "require" (true), name of a file
+    Vocab,
+    Using,
+}
 
 pub struct Parser {
     verbose: bool,
     runner: Runner,
     instructions: Vec<Instruction>,
-    last_number: StackType,                          // for real, imag
-    procedure_lut: HashMap<String, (usize, String)>, // for the parser and print description
-    procedure_state: u8,
-    loop_addr: Vec<usize>,
+    positions: Vec<SrcPos>, // source position of the instructions
+    source: Arc<str>,
+    line_no: usize,
+    pos: SrcPos,            // position of the current token
+    last_number: StackType, // for real, imag
+    last_number_pos: SrcPos,
+    int_literals: bool,   // "intmode", the numbers without fraction are integers
+    exact_literals: bool, // "exactmode", the numbers with fraction are rationals
+    #[cfg(feature = "bigdecimal")]
+    big_literals: bool, // after "N precision", the numbers are big decimals
+    procedure_lut: HashMap<String, Subroutine>, // VOCAB::NAME, or NAME in the root vocabulary
+    defining: Option<String>, // the name of the open definition
+    procedure_state: u8,  // 0: none, 1: waiting for the name, 2: body, 3: locals
+    locals: Vec<String>,  // local names of the current subroutine
+    control: Vec<Control>,
+    control_pos: Vec<SrcPos>, // the words of the open control structures
+    def_pos: Option<SrcPos>,  // ":" of the subroutine being defined
+    name_lut: HashMap<String, Name>, // variables and constants
+    pending_name: Option<Pending>,
+    last_variable: Option<usize>, // for @ and !
+    ticked: Option<usize>,        // "' name", for is
+    def_start: usize,             // address of the current subroutine, for recurse
+    markers: Vec<(String, Mark)>, // marker NAME, in the order of the definitions
+    compact_limit: usize,
+    search_path: Vec<PathBuf>,  // for include, from RPNCALC_PATH
+    included: HashSet<PathBuf>, // for require
+    include_depth: usize,
+    vocabs: Vec<String>,
+    vocab: Option<String>,     // between "vocab NAME" and "end-vocab"
+    search_order: Vec<String>, // "using NAME", the last one first
 }
 
 impl Parser {
@@ -19,233 +115,1137 @@ impl Parser {
             verbose,
             runner: Runner::new(verbose),
             instructions: vec![],
+            positions: vec![],
+            source: "<input>".into(),
+            line_no: 0,
+            pos: SrcPos {
+                file: "<input>".into(),
+                line: 0,
+                col: 0,
+                token: String::new(),
+            },
 
             last_number: StackType::None,
+            last_number_pos: SrcPos {
+                file: "<input>".into(),
+                line: 0,
+                col: 0,
+                token: String::new(),
+            },
+            int_literals: false,
+            exact_literals: false,
+            #[cfg(feature = "bigdecimal")]
+            big_literals: false,
             procedure_lut: HashMap::new(),
+            defining: None,
             procedure_state: 0,
-            loop_addr: vec![],
+            locals: vec![],
+            control: vec![],
+            control_pos: vec![],
+            def_pos: None,
+            name_lut: HashMap::new(),
+            pending_name: None,
+            last_variable: None,
+            ticked: None,
+            markers: vec![],
+            compact_limit: COMPACT_LIMIT,
+            search_path: std::env::var_os("RPNCALC_PATH")
+                .map(|p| std::env::split_paths(&p).collect())
+                .unwrap_or_default(),
+            included: HashSet::new(),
+            include_depth: 0,
+            vocabs: vec![],
+            vocab: None,
+            search_order: vec![],
+            def_start: 0,
         }
     }
 
-    fn get_reg(&mut self) -> Option<u8> {
-        let StackType::Double(a) = self.last_number else {
-            eprintln!("Register number needed before this instruction.");
-            return None;
+    // Name of the file (or other input) for the diagnostics, the line numbers restart from 1.
+    pub fn set_source(&mut self, name: &str) {
+        self.source = name.into();
+        self.line_no = 0;
+    }
+
+    // Directories of the included files, after the directory of the including file
+    pub fn set_search_path(&mut self, dirs: Vec<PathBuf>) {
+        self.search_path = dirs;
+    }
+
+    // Limit of the nested subroutine calls
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.runner.set_max_depth(depth);
+    }
+
+    pub fn stack(&self) -> &[StackType] {
+        self.runner.stack()
+    }
+
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.runner.stop_handle()
+    }
+
+    pub fn quit_requested(&self) -> bool {
+        self.runner.quit_requested()
+    }
+
+    // Push and pop the open control structures, with the position of the word
+    fn open(&mut self, control: Control) {
+        self.control.push(control);
+        self.control_pos.push(self.pos.clone());
+    }
+
+    fn close(&mut self) -> Option<Control> {
+        self.control_pos.pop();
+        self.control.pop()
+    }
+
+    fn emit(&mut self, instr: Instruction) {
+        self.instructions.push(instr);
+        self.positions.push(self.pos.clone());
+    }
+
+    fn get_reg(&mut self, token: &str) -> Result<u8, CalcError> {
+        let Some(a) = real(&self.last_number) else {
+            return Err(CalcError::BadRegister(token.to_string()));
         };
+        if !(0.0..=255.0).contains(&a) {
+            return Err(CalcError::BadRegister(token.to_string()));
+        }
         self.last_number = StackType::None;
-        Some(a as u8)
+        Ok(a as u8)
+    }
+
+    // Compile the pending number (it is waiting for a possible imaginary part).
+    fn flush_number(&mut self) {
+        if self.last_number != StackType::None {
+            self.instructions
+                .push(Instruction::Literal(self.last_number.clone()));
+            self.positions.push(self.last_number_pos.clone());
+            self.last_number = StackType::None;
+        }
+    }
+
+    // Address of the next compiled instruction
+    fn here(&mut self) -> usize {
+        self.runner.get_proglen() + self.instructions.len()
+    }
+
+    // Set the target of the forward jump at addr to the next instruction.
+    fn patch(&mut self, addr: usize) {
+        let target = self.here();
+        match &mut self.instructions[addr - self.runner.get_proglen()] {
+            Instruction::Jz(a) | Instruction::Jmp(a) => *a = target,
+            _ => unreachable!("patch of a non-jump instruction"),
+        }
+    }
+
+    // Run the compiled top-level code of the line before the dictionary changes.
+    fn run_pending(&mut self) -> Result<(), Error> {
+        self.flush_number();
+        self.runner.run(&self.instructions, &self.positions)?;
+        self.instructions.clear();
+        self.positions.clear();
+        Ok(())
+    }
+
+    // Drop the subroutines, names and markers defined after the mark.
+    fn forget(&mut self, mark: Mark) {
+        self.runner.forget(mark);
+        self.procedure_lut = std::mem::take(&mut self.procedure_lut)
+            .into_iter()
+            .filter_map(|(name, s)| Some((name, s.before(mark)?)))
+            .collect();
+        self.name_lut.retain(|_, name| match *name {
+            Name::Variable(slot) | Name::Constant(slot) => slot < mark.names,
+            Name::Deferred(slot) => slot < mark.deferred,
+        });
+        self.markers.retain(|(_, m)| m.prog <= mark.prog);
+    }
+
+    // Key of a subroutine: VOCAB::NAME is direct, NAME is searched in the current
+    // vocabulary, in the search order, and in the root vocabulary.
+    fn resolve(&self, name: &str) -> Option<String> {
+        if name.contains("::") {
+            return self
+                .procedure_lut
+                .contains_key(name)
+                .then(|| name.to_string());
+        }
+        self.vocab
+            .iter()
+            .chain(&self.search_order)
+            .map(|v| format!("{v}::{name}"))
+            .chain(std::iter::once(name.to_string()))
+            .find(|key| self.procedure_lut.contains_key(key))
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Subroutine> {
+        self.procedure_lut.get(&self.resolve(name)?)
+    }
+
+    // Path of an included file: next to the current source, or in the search path
+    fn find_file(&self, name: &str) -> Option<PathBuf> {
+        let dir = Path::new(&*self.source).parent().unwrap_or(Path::new(""));
+        std::iter::once(dir)
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+
+    // Parse the lines of the file; with require only once.
+    fn include(&mut self, name: &str, once: bool) -> Result<(), Error> {
+        let name = match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+            Some(name) => name,
+            None => name,
+        };
+        let Some(path) = self.find_file(name) else {
+            return Err(CalcError::FileNotFound(name.to_string()).into());
+        };
+        let path = path.canonicalize().unwrap_or(path);
+        if once && self.included.contains(&path) {
+            return Ok(());
+        }
+        if self.include_depth >= MAX_INCLUDE {
+            return Err(CalcError::IncludeTooDeep(name.to_string()).into());
+        }
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            return Err(CalcError::FileNotFound(name.to_string()).into());
+        };
+        // before the parse, so a file requiring itself is loaded once
+        let new = self.included.insert(path.clone());
+
+        let (source, line_no) = (self.source.clone(), self.line_no);
+        self.set_source(&path.to_string_lossy());
+        self.include_depth += 1;
+        let mut res = Ok(());
+        for line in text.lines() {
+            res = self.parse_line(line);
+            if res.is_err() || self.quit_requested() {
+                break;
+            }
+        }
+        self.include_depth -= 1;
+        self.source = source;
+        self.line_no = line_no;
+        if res.is_err() && new {
+            self.included.remove(&path); // a later require tries it again
+        }
+        res
+    }
+
+    // Drop the definition replaced by key, unless a forget can bring it back: a marker
+    // or another definition lies between the two. True if it was dropped.
+    fn drop_replaced(&mut self, key: &str) -> bool {
+        let Some(s) = self.procedure_lut.get(key) else {
+            return false;
+        };
+        let Some(old) = s.older.as_deref() else {
+            return false;
+        };
+        let (from, to) = (old.mark.prog, s.mark.prog);
+        let marked = self
+            .markers
+            .iter()
+            .any(|(_, m)| from < m.prog && m.prog <= to)
+            || self
+                .procedure_lut
+                .values()
+                .flat_map(Subroutine::chain)
+                .any(|d| from < d.mark.prog && d.mark.prog < to);
+        if marked {
+            return false;
+        }
+        if let Some(s) = self.procedure_lut.get_mut(key) {
+            s.older = s.older.take().and_then(|old| old.older);
+        }
+        true
+    }
+
+    // Reclaim the code of the dropped subroutines, if nothing calls them.
+    fn compact(&mut self) {
+        let roots: Vec<usize> = self
+            .procedure_lut
+            .values()
+            .flat_map(Subroutine::chain)
+            .map(|s| s.mark.prog)
+            .collect();
+        let reloc = self.runner.compact(&roots);
+        for s in self.procedure_lut.values_mut() {
+            let mut next = Some(s);
+            while let Some(s) = next {
+                s.mark = reloc.mark(s.mark);
+                next = s.older.as_deref_mut();
+            }
+        }
+        for (_, m) in &mut self.markers {
+            *m = reloc.mark(*m);
+        }
+    }
+
+    // Run a pending number at the end of the input; an open word is an error.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let open = if self.pending_name.is_some() {
+            Some(self.pos.clone()) // the word waiting for a name
+        } else {
+            self.control_pos.last().or(self.def_pos.as_ref()).cloned()
+        };
+        if let Some(pos) = open {
+            let mut e = Error::from(CalcError::Unbalanced(pos.token.clone()));
+            e.pos = Some(Box::new(pos));
+            self.reset();
+            return Err(e);
+        }
+        self.flush_number();
+        let res = self.runner.run(&self.instructions, &self.positions);
+        self.instructions.clear();
+        self.positions.clear();
+        res
+    }
+
+    pub fn parse_line(&mut self, line: &str) -> Result<(), Error> {
+        self.line_no += 1;
+        let res = self.parse_tokens(line);
+        if let Err(mut e) = res {
+            if e.pc.is_none() && e.pos.is_none() {
+                e.pos = Some(Box::new(self.pos.clone()));
+            }
+            self.reset();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Drop the half-compiled line or subroutine
+    fn reset(&mut self) {
+        self.instructions.clear();
+        self.positions.clear();
+        self.last_number = StackType::None;
+        self.procedure_state = 0;
+        self.locals.clear();
+        self.control.clear();
+        self.control_pos.clear();
+        self.def_pos = None;
+        self.pending_name = None;
+        self.last_variable = None;
+        self.ticked = None;
+        if let Some(name) = self.defining.take() {
+            if let Some(old) = self.procedure_lut.remove(&name).and_then(|s| s.older) {
+                self.procedure_lut.insert(name, *old);
+            }
+        }
+    }
+
+    // The name after variable, constant, defer, forget, marker, ', is, include and require
+    fn define_name(&mut self, pending: Pending, token: &str) -> Result<(), Error> {
+        match pending {
+            Pending::Variable | Pending::Constant => {
+                if is_number(token) {
+                    return Err(CalcError::BadName(token.to_string()).into());
+                }
+                let constant = pending == Pending::Constant;
+                let slot = match self.name_lut.get(token) {
+                    Some(Name::Variable(slot)) | Some(Name::Constant(slot)) => *slot,
+                    _ => self.runner.add_name(token),
+                };
+                self.runner.set_name_kind(slot, constant);
+                if constant {
+                    self.emit(Instruction::Store(slot));
+                    self.name_lut
+                        .insert(token.to_string(), Name::Constant(slot));
+                } else {
+                    self.name_lut
+                        .insert(token.to_string(), Name::Variable(slot));
+                }
+            }
+            Pending::Defer => {
+                let slot = self.runner.add_deferred(token);
+                self.name_lut
+                    .insert(token.to_string(), Name::Deferred(slot));
+            }
+            Pending::Tick => {
+                let Some(s) = self.lookup(token) else {
+                    return Err(CalcError::UnknownWord(token.to_string()).into());
+                };
+                self.ticked = Some(s.mark.prog);
+            }
+            Pending::Forget => {
+                let Some(s) = self.lookup(token) else {
+                    return Err(CalcError::UnknownWord(token.to_string()).into());
+                };
+                self.forget(s.mark);
+            }
+            Pending::Marker => {
+                self.markers.push((token.to_string(), self.runner.mark()));
+            }
+            Pending::Is(addr) => {
+                let Some(Name::Deferred(slot)) = self.name_lut.get(token) else {
+                    return Err(CalcError::BadDefer(token.to_string()).into());
+                };
+                self.emit(Instruction::SetDeferred(*slot, addr));
+            }
+            Pending::Include(once) => self.include(token, once)?,
+            Pending::Vocab => {
+                if !self.vocabs.iter().any(|v| v == token) {
+                    self.vocabs.push(token.to_string());
+                }
+                self.vocab = Some(token.to_string());
+            }
+            Pending::Using => {
+                if !self.vocabs.iter().any(|v| v == token) {
+                    return Err(CalcError::UnknownWord(token.to_string()).into());
+                }
+                self.search_order.retain(|v| v != token);
+                self.search_order.insert(0, token.to_string());
+            }
+        }
+        Ok(())
     }
 
-    pub fn parse_line(&mut self, line: &str) {
-        for token in line.split('#').next().unwrap().split_whitespace() {
+    fn parse_tokens(&mut self, line: &str) -> Result<(), Error> {
+        for (col, token) in tokenize(line) {
+            self.pos = SrcPos {
+                file: self.source.clone(),
+                line: self.line_no,
+                col,
+                token: token.to_string(),
+            };
             if self.verbose {
                 println!("Debug: parser token: {token}");
             }
+            if let Some(pending) = self.pending_name.take() {
+                self.define_name(pending, token)?;
+                continue;
+            }
+            if self.procedure_state == 3 {
+                // : name {: a b :} ... ;
+                if token == ":}" {
+                    // ";" ends the frame only if there are locals
+                    if !self.locals.is_empty() {
+                        self.emit(Instruction::Locals(self.locals.len()));
+                    }
+                    self.procedure_state = 2;
+                } else if is_number(token) {
+                    return Err(CalcError::BadName(token.to_string()).into());
+                } else {
+                    self.locals.push(token.to_string());
+                }
+                continue;
+            }
+            let ticked = self.ticked.take();
+            if ticked.is_some() && token != "is" {
+                return Err(CalcError::BadDefer(token.to_string()).into());
+            }
+            let variable = self.last_variable.take();
+            if variable.is_some() && token != "@" && token != "!" {
+                return Err(CalcError::BadVariable(token.to_string()).into());
+            }
             let mut last_command_not_parse_double = true;
+            let emitted = self.instructions.len(); // start of the instructions of this token
             match token {
                 // Stack operations
-                "dup" => self.instructions.push(Instruction::Dup),
-                "drop" => self.instructions.push(Instruction::Drop),
-                "over" => self.instructions.push(Instruction::Over),
-                "rot" => self.instructions.push(Instruction::Rot),
-                "swap" => self.instructions.push(Instruction::Swap),
-                "clear" => self.instructions.push(Instruction::Clear),
-                "dumpstack" | "ds" => self.instructions.push(Instruction::DumpStack),
+                "dup" => self.emit(Instruction::Dup),
+                "drop" => self.emit(Instruction::Drop),
+                "over" => self.emit(Instruction::Over),
+                "rot" => self.emit(Instruction::Rot),
+                "swap" => self.emit(Instruction::Swap),
+                "clear" => self.emit(Instruction::Clear),
+                "dumpstack" | "ds" => self.emit(Instruction::DumpStack),
 
                 // Basic arithmetic
-                "+" | "add" => self.instructions.push(Instruction::Add),
-                "-" | "sub" => self.instructions.push(Instruction::Sub),
-                "*" | "mul" => self.instructions.push(Instruction::Mul),
-                "/" | "div" => self.instructions.push(Instruction::Div),
-                "and" => self.instructions.push(Instruction::And),
-                "or" => self.instructions.push(Instruction::Or),
-                "xor" => self.instructions.push(Instruction::Xor),
-                "neg" => self.instructions.push(Instruction::Neg),
-                "shl" => self.instructions.push(Instruction::Shl),
-                "shr" => self.instructions.push(Instruction::Shr),
-                "abs" => self.instructions.push(Instruction::Abs),
-                "floor" => self.instructions.push(Instruction::Floor),
-                "ceil" => self.instructions.push(Instruction::Ceil),
-                "round" => self.instructions.push(Instruction::Round),
+                "+" | "add" => self.emit(Instruction::Add),
+                "-" | "sub" => self.emit(Instruction::Sub),
+                "*" | "mul" => self.emit(Instruction::Mul),
+                "/" | "div" => self.emit(Instruction::Div),
+                "and" => self.emit(Instruction::And),
+                "or" => self.emit(Instruction::Or),
+                "xor" => self.emit(Instruction::Xor),
+                "neg" => self.emit(Instruction::Neg),
+                "shl" => self.emit(Instruction::Shl),
+                "shr" => self.emit(Instruction::Shr),
+                "rol" => self.emit(Instruction::Rol),
+                "ror" => self.emit(Instruction::Ror),
+                "popcount" => self.emit(Instruction::Popcount),
+                "clz" => self.emit(Instruction::Clz),
+                ">int" => self.emit(Instruction::ToInt),
+                ">float" => self.emit(Instruction::ToFloat),
+                ">frac" => self.emit(Instruction::ToFrac),
+                "intmode" | "exactmode" | "floatmode" => {
+                    self.int_literals = token != "floatmode";
+                    self.exact_literals = token == "exactmode";
+                    self.emit(Instruction::Exact(self.exact_literals));
+                }
+                "abs" => self.emit(Instruction::Abs),
+                "floor" => self.emit(Instruction::Floor),
+                "ceil" => self.emit(Instruction::Ceil),
+                "round" => self.emit(Instruction::Round),
+                "sqrt" => self.emit(Instruction::Sqrt),
+                "cbrt" => self.emit(Instruction::Cbrt),
+                "trunc" => self.emit(Instruction::Trunc),
+                "sign" => self.emit(Instruction::Sign),
+                "fmod" => self.emit(Instruction::Fmod),
+                "hypot" => self.emit(Instruction::Hypot),
+                "atan2" => self.emit(Instruction::Atan2),
+                "min" => self.emit(Instruction::Min),
+                "max" => self.emit(Instruction::Max),
 
                 // Trigonometric function
-                "cosr" => self.instructions.push(Instruction::CosR),
-                "sinr" => self.instructions.push(Instruction::SinR),
-                "tanr" => self.instructions.push(Instruction::TanR),
-                "cosd" => self.instructions.push(Instruction::CosD),
-                "sind" => self.instructions.push(Instruction::SinD),
-                "tand" => self.instructions.push(Instruction::TanD),
-                "acosr" => self.instructions.push(Instruction::AcosR),
-                "asinr" => self.instructions.push(Instruction::AsinR),
-                "atanr" => self.instructions.push(Instruction::AtanR),
-                "acosd" => self.instructions.push(Instruction::AcosD),
-                "asind" => self.instructions.push(Instruction::AsinD),
-                "atand" => self.instructions.push(Instruction::AtanD),
+                "cosr" => self.emit(Instruction::CosR),
+                "sinr" => self.emit(Instruction::SinR),
+                "tanr" => self.emit(Instruction::TanR),
+                "cosd" => self.emit(Instruction::CosD),
+                "sind" => self.emit(Instruction::SinD),
+                "tand" => self.emit(Instruction::TanD),
+                "acosr" => self.emit(Instruction::AcosR),
+                "asinr" => self.emit(Instruction::AsinR),
+                "atanr" => self.emit(Instruction::AtanR),
+                "acosd" => self.emit(Instruction::AcosD),
+                "asind" => self.emit(Instruction::AsinD),
+                "atand" => self.emit(Instruction::AtanD),
+                "sinh" => self.emit(Instruction::Sinh),
+                "cosh" => self.emit(Instruction::Cosh),
+                "tanh" => self.emit(Instruction::Tanh),
+                "asinh" => self.emit(Instruction::Asinh),
+                "acosh" => self.emit(Instruction::Acosh),
+                "atanh" => self.emit(Instruction::Atanh),
+
+                // Special functions
+                "gamma" => self.emit(Instruction::Gamma),
+                "lgamma" => self.emit(Instruction::Lgamma),
+                "erf" => self.emit(Instruction::Erf),
+                "erfc" => self.emit(Instruction::Erfc),
+                "j0" => self.emit(Instruction::BesselJ0),
+                "j1" => self.emit(Instruction::BesselJ1),
+                "jn" => self.emit(Instruction::BesselJn),
+                "i0" => self.emit(Instruction::BesselI0),
+                "n!" => self.emit(Instruction::Factorial),
+                "ncr" | "nCr" => self.emit(Instruction::Ncr),
+                "npr" | "nPr" => self.emit(Instruction::Npr),
+
+                // Number theory
+                "gcd" => self.emit(Instruction::Gcd),
+                "lcm" => self.emit(Instruction::Lcm),
+                "mod" | "%" => self.emit(Instruction::Mod),
+                "modpow" => self.emit(Instruction::ModPow),
+                "modinv" => self.emit(Instruction::ModInv),
+                "isprime" => self.emit(Instruction::IsPrime),
+                "nextprime" => self.emit(Instruction::NextPrime),
+                "factor" => self.emit(Instruction::Factor),
+                "vfactor" => {
+                    let reg = self.get_reg(token)?;
+                    self.emit(Instruction::VFactor(reg));
+                }
 
                 // Logarithm and exponential
-                "loge" => self.instructions.push(Instruction::Loge),
-                "log2" => self.instructions.push(Instruction::Log2),
-                "log10" => self.instructions.push(Instruction::Log10),
-                "logx" => self.instructions.push(Instruction::Logx),
-                "expe" => self.instructions.push(Instruction::Expe),
-                "exp2" => self.instructions.push(Instruction::Exp2),
-                "exp10" => self.instructions.push(Instruction::Exp10),
-                "expx" => self.instructions.push(Instruction::Expx),
-                ">" => self.instructions.push(Instruction::Gt),
-                "<" => self.instructions.push(Instruction::Lt),
-                ">=" => self.instructions.push(Instruction::Ge),
-                "<=" => self.instructions.push(Instruction::Le),
-                "=" => self.instructions.push(Instruction::Eq),
+                "loge" => self.emit(Instruction::Loge),
+                "log2" => self.emit(Instruction::Log2),
+                "log10" => self.emit(Instruction::Log10),
+                "logx" => self.emit(Instruction::Logx),
+                "expe" => self.emit(Instruction::Expe),
+                "exp2" => self.emit(Instruction::Exp2),
+                "exp10" => self.emit(Instruction::Exp10),
+                "expx" | "pow" => self.emit(Instruction::Expx),
+                ">" => self.emit(Instruction::Gt),
+                "<" => self.emit(Instruction::Lt),
+                ">=" => self.emit(Instruction::Ge),
+                "<=" => self.emit(Instruction::Le),
+                "=" => self.emit(Instruction::Eq),
 
                 // Complex
-                "real" => self.instructions.push(Instruction::Real),
-                "imag" => self.instructions.push(Instruction::Imag),
-                "r2c" => self.instructions.push(Instruction::R2c),
+                "real" => self.emit(Instruction::Real),
+                "imag" => self.emit(Instruction::Imag),
+                "r2c" => self.emit(Instruction::R2c),
+                "p2c" => self.emit(Instruction::P2c),
+                "c2p" => self.emit(Instruction::C2p),
+                "arg" => self.emit(Instruction::Arg),
+                "conj" => self.emit(Instruction::Conj),
+                "cplxmode" => self.emit(Instruction::CplxMode(true)),
+                "realmode" => self.emit(Instruction::CplxMode(false)),
 
                 // Print and related
-                "frdigit" => self.instructions.push(Instruction::FractionalDigit),
-                "p" | "print" => self.instructions.push(Instruction::Print),
+                "frdigit" => self.emit(Instruction::FractionalDigit),
+                #[cfg(feature = "bigdecimal")]
+                "precision" => {
+                    // the number is read at parse time, the literals after it are big decimals
+                    let digits = real(&self.last_number).unwrap_or(-1.0);
+                    if !(0.0..=MAX_PRECISION).contains(&digits) || digits.fract() != 0.0 {
+                        return Err(CalcError::BadNumber(token.to_string()).into());
+                    }
+                    self.last_number = StackType::None;
+                    self.big_literals = digits > 0.0;
+                    if self.big_literals {
+                        self.emit(Instruction::Precision(digits as u64));
+                    }
+                }
+                "p" | "print" => self.emit(Instruction::Print),
+                "hex" => self.emit(Instruction::Radix(16)),
+                "dec" => self.emit(Instruction::Radix(10)),
+                "oct" => self.emit(Instruction::Radix(8)),
+                "bin" => self.emit(Instruction::Radix(2)),
+                "wordsize" => self.emit(Instruction::WordSize),
+                "signed" => self.emit(Instruction::Signed(true)),
+                "unsigned" => self.emit(Instruction::Signed(false)),
+                "polar" => self.emit(Instruction::Polar(true)),
+                "rect" => self.emit(Instruction::Polar(false)),
+                "degrees" => self.emit(Instruction::Degrees(true)),
+                "radians" => self.emit(Instruction::Degrees(false)),
+
+                // Strings
+                "type" => self.emit(Instruction::Type),
+                "cr" => self.emit(Instruction::Cr),
+                "len" => self.emit(Instruction::Len),
+                ">str" => self.emit(Instruction::ToStr),
+                "str>" => self.emit(Instruction::StrTo),
+                t if t.starts_with('"') || t.starts_with(".\"") => {
+                    let (text, print) = match t.strip_prefix('.') {
+                        Some(t) => (t, true),
+                        None => (t, false),
+                    };
+                    if text.len() < 2 || !text.ends_with('"') {
+                        return Err(CalcError::BadString(token.to_string()).into());
+                    }
+                    self.flush_number();
+                    let text = text[1..text.len() - 1].to_string();
+                    self.emit(Instruction::Literal(StackType::Str(text)));
+                    if print {
+                        self.emit(Instruction::Type);
+                    }
+                }
 
                 // Register
                 "save" => {
-                    let Some(reg) = self.get_reg() else { break };
-                    self.instructions.push(Instruction::Save(reg));
+                    let reg = self.get_reg(token)?;
+                    self.emit(Instruction::Save(reg));
                 }
                 "load" => {
-                    let Some(reg) = self.get_reg() else { break };
-                    self.instructions.push(Instruction::Load(reg));
+                    let reg = self.get_reg(token)?;
+                    self.emit(Instruction::Load(reg));
                 }
                 "creg" => {
-                    let Some(reg) = self.get_reg() else { break };
-                    self.instructions.push(Instruction::Creg(reg));
+                    let reg = self.get_reg(token)?;
+                    self.emit(Instruction::Creg(reg));
+                }
+                "clregs" => self.emit(Instruction::Clregs),
+
+                // Named variables and constants: variable NAME, NAME @, NAME !, VALUE constant NAME
+                "variable" | "constant" => {
+                    self.flush_number();
+                    self.pending_name = Some(if token == "variable" {
+                        Pending::Variable
+                    } else {
+                        Pending::Constant
+                    });
+                }
+
+                // Recursion and forward reference: recurse, defer NAME, ' SUBROUTINE is NAME
+                "recurse" => {
+                    if self.procedure_state != 2 {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    }
+                    self.emit(Instruction::Call(self.def_start));
+                }
+                "defer" => {
+                    self.flush_number();
+                    self.pending_name = Some(Pending::Defer);
+                }
+                "'" => {
+                    self.flush_number();
+                    self.pending_name = Some(Pending::Tick);
                 }
-                "clregs" => self.instructions.push(Instruction::Clregs),
-                "dumpreg" | "dr" => self.instructions.push(Instruction::DumpReg),
+                "is" => {
+                    let Some(addr) = ticked else {
+                        return Err(CalcError::BadDefer(token.to_string()).into());
+                    };
+                    self.pending_name = Some(Pending::Is(addr));
+                }
+                "@" | "!" => {
+                    let Some(slot) = variable else {
+                        return Err(CalcError::BadVariable(token.to_string()).into());
+                    };
+                    if token == "@" {
+                        self.emit(Instruction::Fetch(slot));
+                    } else {
+                        self.emit(Instruction::Store(slot));
+                    }
+                }
+                "dumpreg" | "dr" => self.emit(Instruction::DumpReg),
 
                 // Vector
                 "vreal" => {
-                    let Some(reg) = self.get_reg() else { break };
-                    self.instructions.push(Instruction::Vreal(reg));
+                    let reg = self.get_reg(token)?;
+                    self.emit(Instruction::Vreal(reg));
                 }
                 "vcplx" => {
-                    let Some(reg) = self.get_reg() else { break };
-                    self.instructions.push(Instruction::Vcplx(reg));
+                    let reg = self.get_reg(token)?;
+                    self.emit(Instruction::Vcplx(reg));
                 }
                 "vsave" => {
-                    let Some(reg) = self.get_reg() else { break };
-                    self.instructions.push(Instruction::Vsave(reg));
+                    let reg = self.get_reg(token)?;
+                    self.emit(Instruction::Vsave(reg));
                 }
                 "vload" => {
-                    let Some(reg) = self.get_reg() else { break };
-                    self.instructions.push(Instruction::Vload(reg));
+                    let reg = self.get_reg(token)?;
+                    self.emit(Instruction::Vload(reg));
                 }
                 "cvec" => {
-                    let Some(reg) = self.get_reg() else { break };
-                    self.instructions.push(Instruction::Cvec(reg));
+                    let reg = self.get_reg(token)?;
+                    self.emit(Instruction::Cvec(reg));
                 }
-                "clvecs" => self.instructions.push(Instruction::Clvecs),
-                "dumpvec" | "dv" => self.instructions.push(Instruction::DumpVec),
+                "clvecs" => self.emit(Instruction::Clvecs),
+                "dumpvec" | "dv" => self.emit(Instruction::DumpVec),
 
                 // Procedure and loop:
                 ":" => {
-                    self.runner.run(&self.instructions);
-                    self.instructions.clear();
+                    if self.procedure_state != 0 || !self.control.is_empty() {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    }
+                    self.run_pending()?;
                     self.procedure_state = 1;
+                    self.def_pos = Some(self.pos.clone());
                 }
                 ";" => {
-                    self.instructions.push(Instruction::Ret);
-                    self.runner.add_instr(&self.instructions);
+                    if self.procedure_state != 2 || !self.control.is_empty() {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    }
+                    self.flush_number();
+                    if !self.locals.is_empty() {
+                        self.emit(Instruction::EndLocals);
+                        self.locals.clear();
+                    }
+                    self.emit(Instruction::Ret);
+                    self.runner.add_instr(&self.instructions, &self.positions);
                     self.instructions.clear();
+                    self.positions.clear();
                     self.procedure_state = 0;
+                    self.def_pos = None;
+                    if let Some(key) = self.defining.take() {
+                        if self.drop_replaced(&key) {
+                            self.compact();
+                        }
+                    }
+                }
+                // Load a file: include FILE, require FILE
+                "include" | "require" => {
+                    if self.procedure_state != 0 || !self.control.is_empty() {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    }
+                    self.run_pending()?;
+                    self.pending_name = Some(Pending::Include(token == "require"));
+                }
+                // Forget a subroutine and the later definitions: forget NAME, marker NAME ... NAME
+                "forget" | "marker" => {
+                    if self.procedure_state != 0 || !self.control.is_empty() {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    }
+                    self.run_pending()?;
+                    self.pending_name = Some(if token == "forget" {
+                        Pending::Forget
+                    } else {
+                        Pending::Marker
+                    });
+                }
+                // Local variables, at the start of the subroutine body
+                "{:" => {
+                    if self.procedure_state != 2
+                        || !self.instructions.is_empty()
+                        || self.last_number != StackType::None
+                        || !self.control.is_empty()
+                    {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    }
+                    self.procedure_state = 3;
+                }
+                // Quotation: { ... } pushes the address of the code
+                "{" => {
+                    self.flush_number();
+                    let addr = self.here();
+                    self.emit(Instruction::Literal(StackType::Quote(addr + 2)));
+                    self.emit(Instruction::Jmp(0));
+                    self.open(Control::Quote(addr + 1));
+                }
+                "}" => {
+                    self.flush_number();
+                    let Some(Control::Quote(addr)) = self.close() else {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    };
+                    self.emit(Instruction::Ret);
+                    self.patch(addr);
+                }
+                "call" => self.emit(Instruction::CallQuote),
+                "times" => {
+                    self.emit(Instruction::Times);
+                    self.emit(Instruction::TimesStep);
+                }
+                "ifte" => self.emit(Instruction::Ifte),
+                "catch" => {
+                    self.emit(Instruction::Catch);
+                    self.emit(Instruction::EndCatch);
+                }
+                "throw" => self.emit(Instruction::Throw),
+                // Vocabularies: vocab NAME ... end-vocab, NAME::WORD, using NAME
+                "vocab" | "using" => {
+                    if self.procedure_state != 0 || !self.control.is_empty() {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    }
+                    self.flush_number();
+                    self.pending_name = Some(if token == "vocab" {
+                        Pending::Vocab
+                    } else {
+                        Pending::Using
+                    });
+                }
+                "end-vocab" => {
+                    if self.procedure_state != 0 || self.vocab.take().is_none() {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    }
                 }
                 "dumpsr" | "dsr" => {
-                    for p in &self.procedure_lut {
-                        println!("Subroutine   {}", p.1 .1);
+                    let mut words: Vec<_> = self.procedure_lut.iter().collect();
+                    words.sort_by(|a, b| a.0.cmp(b.0));
+                    for vocab in std::iter::once(None).chain(self.vocabs.iter().map(Some)) {
+                        if let Some(vocab) = vocab {
+                            println!("Vocabulary {vocab}:");
+                        }
+                        for (key, s) in &words {
+                            if key.rsplit_once("::").map(|k| k.0) == vocab.map(String::as_str) {
+                                println!("Subroutine   {}", s.description);
+                            }
+                        }
+                    }
+                    if !self.search_order.is_empty() {
+                        println!("Search order: {}", self.search_order.join(" "));
                     }
                 }
-                "[" => self
-                    .loop_addr
-                    .push(self.runner.get_proglen() + self.instructions.len()),
-                "]" => self
-                    .instructions
-                    .push(Instruction::Jnz(self.loop_addr.pop().unwrap())),
+                "[" => {
+                    self.flush_number();
+                    let addr = self.here();
+                    self.open(Control::Loop(addr));
+                }
+                "]" => {
+                    self.flush_number();
+                    let Some(Control::Loop(addr)) = self.close() else {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    };
+                    self.emit(Instruction::Jnz(addr));
+                }
+
+                // Counted loop: LIMIT START do ... loop
+                "do" => {
+                    self.flush_number();
+                    self.emit(Instruction::Do);
+                    let addr = self.here();
+                    self.open(Control::Do(addr));
+                }
+                "loop" | "+loop" => {
+                    self.flush_number();
+                    let Some(Control::Do(addr)) = self.close() else {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    };
+                    if token == "loop" {
+                        self.emit(Instruction::Loop(addr));
+                    } else {
+                        self.emit(Instruction::PlusLoop(addr));
+                    }
+                }
+                "i" => self.emit(Instruction::I),
+                "j" => self.emit(Instruction::J),
+
+                // Pre- and post-test loops: begin ... flag while ... repeat, begin ... flag until
+                "begin" => {
+                    self.flush_number();
+                    let addr = self.here();
+                    self.open(Control::Begin(addr));
+                }
+                "until" => {
+                    self.flush_number();
+                    let Some(Control::Begin(addr)) = self.close() else {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    };
+                    self.emit(Instruction::Jz(addr));
+                }
+                "while" => {
+                    self.flush_number();
+                    let Some(Control::Begin(_)) = self.control.last() else {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    };
+                    let addr = self.here();
+                    self.emit(Instruction::Jz(0));
+                    self.open(Control::While(addr));
+                }
+                "repeat" => {
+                    self.flush_number();
+                    let (Some(Control::While(while_addr)), Some(Control::Begin(addr))) =
+                        (self.close(), self.close())
+                    else {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    };
+                    self.emit(Instruction::Jmp(addr));
+                    self.patch(while_addr);
+                }
+
+                // Condition: flag if ... else ... then
+                "if" => {
+                    self.flush_number();
+                    let addr = self.here();
+                    self.emit(Instruction::Jz(0));
+                    self.open(Control::If(addr));
+                }
+                "else" => {
+                    self.flush_number();
+                    let Some(Control::If(if_addr)) = self.close() else {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    };
+                    let addr = self.here();
+                    self.emit(Instruction::Jmp(0));
+                    self.patch(if_addr);
+                    self.open(Control::Else(addr));
+                }
+                "then" => {
+                    self.flush_number();
+                    let (Some(Control::If(addr)) | Some(Control::Else(addr))) = self.close() else {
+                        return Err(CalcError::Unbalanced(token.to_string()).into());
+                    };
+                    self.patch(addr);
+                }
 
                 // Interpreter direct func
                 "help" => {
                     help();
                 }
                 "quit" | "bye" | "exit" | "q" => {
-                    self.instructions.push(Instruction::Quit);
+                    self.emit(Instruction::Quit);
                 }
                 _ => {
                     if self.procedure_state == 1 {
-                        self.procedure_lut.insert(
-                            token.to_string(),
-                            (
-                                self.runner.get_proglen() + self.instructions.len(),
-                                line.to_string(),
-                            ),
-                        );
+                        if is_number(token) {
+                            return Err(CalcError::BadName(token.to_string()).into());
+                        }
+                        // inserted here, so the subroutine can call itself by its name
+                        self.def_start = self.here();
+                        let key = match &self.vocab {
+                            Some(vocab) if !token.contains("::") => format!("{vocab}::{token}"),
+                            _ => token.to_string(),
+                        };
+                        let s = Subroutine {
+                            mark: self.runner.mark(),
+                            description: line.to_string(),
+                            older: self.procedure_lut.remove(&key).map(Box::new),
+                        };
+                        self.procedure_lut.insert(key.clone(), s);
+                        self.defining = Some(key);
                         self.procedure_state = 2;
-                    } else if let Some((call_ptr, _description)) = self.procedure_lut.get(token) {
+                    } else if let Some(idx) = self.locals.iter().position(|l| l == token) {
+                        // a quotation runs later, in the frame of its caller
+                        if self.control.iter().any(|c| matches!(c, Control::Quote(_))) {
+                            return Err(CalcError::UnknownWord(token.to_string()).into());
+                        }
+                        self.emit(Instruction::LocalGet(idx));
+                    } else if let Some(s) = self.lookup(token) {
                         // token -> call subrutin
-                        self.instructions.push(Instruction::Call(*call_ptr));
+                        self.emit(Instruction::Call(s.mark.prog));
+                    } else if let Some(name) = self.name_lut.get(token) {
+                        let name = *name;
+                        self.flush_number();
+                        match name {
+                            Name::Variable(slot) => self.last_variable = Some(slot),
+                            Name::Constant(slot) => self.emit(Instruction::Fetch(slot)),
+                            Name::Deferred(slot) => self.emit(Instruction::CallDeferred(slot)),
+                        }
+                    } else if let Some(n) = self.markers.iter().rposition(|(m, _)| m == token) {
+                        // marker: forget it and the later definitions
+                        if self.procedure_state != 0 || !self.control.is_empty() {
+                            return Err(CalcError::Unbalanced(token.to_string()).into());
+                        }
+                        self.run_pending()?;
+                        let mark = self.markers[n].1;
+                        self.markers.truncate(n);
+                        self.forget(mark);
                     } else if token.as_bytes()[0].is_ascii_digit() || token.as_bytes()[0] == b'-' {
                         // Possible number (real or imag).
                         // Imag check --> 4.32j
                         if token.as_bytes().last().unwrap() == &b'j' {
                             let t2 = &token[0..token.len() - 1];
                             let Ok(imag) = t2.parse::<f64>() else {
-                                eprintln!("Number error");
-                                break;
+                                return Err(CalcError::BadNumber(token.to_string()).into());
                             };
                             // if prevous was a normal Double, it is the real part of complex.
-                            let cmplx = if let StackType::Double(a) = self.last_number {
-                                Complex::new(a, imag)
-                            } else {
-                                Complex::new(0.0, imag)
-                            };
-                            self.instructions
-                                .push(Instruction::Literal(StackType::Complex(cmplx)));
+                            let cmplx = Complex::new(real(&self.last_number).unwrap_or(0.0), imag);
+                            if self.last_number != StackType::None {
+                                self.pos = self.last_number_pos.clone();
+                            }
+                            self.emit(Instruction::Literal(StackType::Complex(cmplx)));
                             self.last_number = StackType::None;
+                        } else if let Some((mag, phase)) = token.split_once('@') {
+                            // polar: 1.5@30d in degrees, 1.5@0.52 or 1.5@0.52r in radians
+                            let (phase, degrees) = match phase.strip_suffix('d') {
+                                Some(phase) => (phase, true),
+                                None => (phase.strip_suffix('r').unwrap_or(phase), false),
+                            };
+                            let (Ok(mag), Ok(phase)) = (mag.parse::<f64>(), phase.parse::<f64>())
+                            else {
+                                return Err(CalcError::BadNumber(token.to_string()).into());
+                            };
+                            let phase = if degrees { phase.to_radians() } else { phase };
+                            let cmplx = Complex::from_polar(mag, phase);
+                            self.emit(Instruction::Literal(StackType::Complex(cmplx)));
                         } else {
                             // Double or real part ... if prevous was a normal Double, write
-                            if let StackType::Double(a) = self.last_number {
-                                self.instructions
-                                    .push(Instruction::Literal(StackType::Double(a)));
-                            }
-                            let Ok(number) = token.parse::<f64>() else {
-                                eprintln!("Number error");
-                                break;
+                            self.flush_number();
+                            let number = match (parse_radix(token), token.parse::<i64>()) {
+                                (Some(number), _) => StackType::Int(number?),
+                                (None, Ok(number)) if self.int_literals => StackType::Int(number),
+                                _ if self.exact_literals => match parse_decimal(token) {
+                                    Some(number) => rational(number),
+                                    None => {
+                                        return Err(CalcError::BadNumber(token.to_string()).into())
+                                    }
+                                },
+                                #[cfg(feature = "bigdecimal")]
+                                _ if self.big_literals => match token.parse::<BigDecimal>() {
+                                    // the digits of 1e100000000 would be aligned in the sums
+                                    Ok(number)
+                                        if number.as_bigint_and_scale().1.unsigned_abs()
+                                            > MAX_EXACT_EXP =>
+                                    {
+                                        return Err(CalcError::OutOfRange.into())
+                                    }
+                                    Ok(number) => StackType::Big(number),
+                                    Err(_) => {
+                                        return Err(CalcError::BadNumber(token.to_string()).into())
+                                    }
+                                },
+                                _ => match token.parse::<f64>() {
+                                    Ok(number) => StackType::Double(number),
+                                    Err(_) => {
+                                        return Err(CalcError::BadNumber(token.to_string()).into())
+                                    }
+                                },
                             };
-                            self.last_number = StackType::Double(number);
+                            self.last_number = number;
+                            self.last_number_pos = self.pos.clone();
                             last_command_not_parse_double = false;
                         }
                     } else {
-                        eprintln!("Not a number, invalid command. Please type 'help'.");
+                        return Err(CalcError::UnknownWord(token.to_string()).into());
                     }
                 }
             } // match
 
             // if the number storeable - does not have imaginary part
-            if last_command_not_parse_double {
-                if let StackType::Double(_) = self.last_number {
-                    if !self.instructions.is_empty() {
-                        let last_instr = self.instructions.pop().unwrap();
-                        self.instructions
-                            .push(Instruction::Literal(self.last_number));
-                        self.instructions.push(last_instr);
-                    } else {
-                        self.instructions
-                            .push(Instruction::Literal(self.last_number));
-                    }
-                    self.last_number = StackType::None;
-                }
+            if last_command_not_parse_double && self.last_number != StackType::None {
+                // before the instructions of this token
+                let at = emitted.min(self.instructions.len());
+                self.instructions
+                    .insert(at, Instruction::Literal(self.last_number.clone()));
+                self.positions.insert(at, self.last_number_pos.clone());
+                self.last_number = StackType::None;
             }
         } // for token
-        if self.procedure_state == 0 && !self.instructions.is_empty() {
-            self.runner.run(&self.instructions);
+          // run it, if it is not inside of a subroutine, a loop or a condition
+        if self.procedure_state == 0 && self.control.is_empty() && !self.instructions.is_empty() {
+            self.runner.run(&self.instructions, &self.positions)?;
             self.instructions.clear();
+            self.positions.clear();
+            // the top-level code with quotations is kept, reclaim it from time to time
+            if self.runner.get_proglen() > self.compact_limit {
+                self.compact();
+                self.compact_limit = (2 * self.runner.get_proglen()).max(COMPACT_LIMIT);
+            }
         }
+        Ok(())
     } // end fn parse
 } // end Parse
+
+// The token is read as a number, e.g. 1, -2.5, 3j, 1@90d or 0x1F
+fn is_number(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+        && (token
+            .strip_suffix('j')
+            .unwrap_or(token)
+            .parse::<f64>()
+            .is_ok()
+            || token.contains('@')
+            || parse_radix(token).is_some())
+}
+
+// Integer literal with a radix prefix: 0x1F, 0o17, 0b1010, '_' separates the digits.
+// None without prefix. Up to 64 bits, 0xFFFF_FFFF_FFFF_FFFF is -1.
+pub(crate) fn parse_radix(text: &str) -> Option<Result<i64, CalcError>> {
+    let (negative, t) = match text.strip_prefix('-') {
+        Some(t) => (true, t),
+        None => (false, text),
+    };
+    let radix = match t.get(..2)? {
+        "0x" | "0X" => 16,
+        "0o" | "0O" => 8,
+        "0b" | "0B" => 2,
+        _ => return None,
+    };
+    let digits = t[2..].replace('_', "");
+    let number = match u64::from_str_radix(&digits, radix) {
+        Ok(n) if !(digits.starts_with('+') || negative && n > 1 << 63) => n as i64,
+        _ => return Some(Err(CalcError::BadNumber(text.to_string()))),
+    };
+    Some(Ok(if negative {
+        number.wrapping_neg()
+    } else {
+        number
+    }))
+}
+
+// Exact value of a decimal literal: 1.25 is 5/4, 1e-3 is 1/1000
+fn parse_decimal(text: &str) -> Option<BigRational> {
+    let (mantissa, exp) = match text.split_once(['e', 'E']) {
+        Some((m, e)) => (m, e.parse::<i64>().ok()?),
+        None => (text, 0),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if !frac.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let digits: BigInt = format!("{int}{frac}").parse().ok()?;
+    let exp = exp.checked_sub(frac.len() as i64)?;
+    if exp.unsigned_abs() > MAX_EXACT_EXP {
+        return None;
+    }
+    let scale = BigInt::from(10).pow(exp.unsigned_abs() as u32);
+    Some(if exp >= 0 {
+        BigRational::from_integer(digits * scale)
+    } else {
+        BigRational::new(digits, scale)
+    })
+}
+
+// Split the line to (column, token) pairs, the comment starts with '#'.
+// "text" and ."text" are one token, with the spaces and '#' inside.
+fn tokenize(line: &str) -> Vec<(usize, &str)> {
+    let mut tokens = vec![];
+    let mut start = None; // column and byte index of the current token
+    let mut in_string = false;
+    for (col, (i, c)) in line.char_indices().enumerate() {
+        if in_string {
+            if c == '"' {
+                if let Some((col0, i0)) = start.take() {
+                    tokens.push((col0 + 1, &line[i0..=i]));
+                }
+                in_string = false;
+            }
+        } else if c == '"' && start.is_none_or(|(_, i0)| &line[i0..i] == ".") {
+            start = start.or(Some((col, i)));
+            in_string = true;
+        } else if c.is_whitespace() || c == '#' {
+            if let Some((col0, i0)) = start.take() {
+                tokens.push((col0 + 1, &line[i0..i]));
+            }
+            if c == '#' {
+                return tokens;
+            }
+        } else if start.is_none() {
+            start = Some((col, i));
+        }
+    }
+    if let Some((col0, i0)) = start {
+        tokens.push((col0 + 1, &line[i0..]));
+    }
+    tokens
+}
@@ -1,8 +1,28 @@
+#[cfg(feature = "bigdecimal")]
+use crate::bigmath;
+use crate::error::{CalcError, Error, SrcPos};
 use crate::instructions::{Instruction, StackType};
+use crate::numtheory;
+use crate::parser::parse_radix;
+#[cfg(feature = "bigdecimal")]
+use crate::parser::MAX_EXACT_EXP;
+use crate::special;
+#[cfg(feature = "bigdecimal")]
+use bigdecimal::RoundingMode;
+use num_bigint::BigInt;
 use num_complex::Complex;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, One, Signed, ToPrimitive, Zero};
+use std::cmp::Ordering::{Equal, Greater, Less};
+use std::f64::consts::PI;
+use std::io::Write;
+use std::ops::Range;
 use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc};
 
 const MAX_STACK: usize = 1_000_000;
+const MAX_RET_STACK: usize = 100_000;
+const MAX_VECTOR: usize = 1 << 24;
+const MAX_FACTORS: i64 = 10_000; // of the exact n!, nCr and nPr
 
 #[derive(Debug, PartialEq)]
 enum Type {
@@ -16,29 +36,166 @@ struct VectorType {
     vector: Vec<f64>,
 }
 
+#[derive(Debug)]
+struct NamedSlot {
+    name: String,
+    constant: bool,
+    value: StackType,
+}
+
+// Value of a real number: Double, Int, Rational (or big decimal)
+pub(crate) fn real(a: &StackType) -> Option<f64> {
+    match a {
+        StackType::Double(a) => Some(*a),
+        StackType::Int(a) => Some(*a as f64),
+        StackType::Rational(a) => a.to_f64(),
+        #[cfg(feature = "bigdecimal")]
+        StackType::Big(a) => a.to_f64(),
+        _ => None,
+    }
+}
+
+// Value of a real or complex number
+fn complex(a: &StackType) -> Option<Complex<f64>> {
+    match a {
+        StackType::Complex(a) => Some(*a),
+        a => real(a).map(|d| Complex::new(d, 0.0)),
+    }
+}
+
+// Degrees to radians
+fn deg<T: std::ops::Mul<f64, Output = T>>(a: T) -> T {
+    a * (PI / 180.)
+}
+
+// The false of the conditions, and the zero divisor
+fn is_zero(a: &StackType) -> bool {
+    match a {
+        #[cfg(feature = "bigdecimal")]
+        StackType::Big(a) => a.is_zero(),
+        StackType::Rational(a) => a.is_zero(),
+        StackType::Complex(a) => a.re == 0.0 && a.im == 0.0,
+        a => real(a) == Some(0.0),
+    }
+}
+
+// The normalized rational number, Int if it is a 64 bit integer
+pub(crate) fn rational(a: BigRational) -> StackType {
+    match a.to_integer().to_i64() {
+        Some(n) if a.is_integer() => StackType::Int(n),
+        _ => StackType::Rational(a),
+    }
+}
+
+// Exact value of Int and Rational
+fn to_rational(a: &StackType) -> Option<BigRational> {
+    match a {
+        StackType::Int(a) => Some(BigRational::from_integer(BigInt::from(*a))),
+        StackType::Rational(a) => Some(a.clone()),
+        _ => None,
+    }
+}
+
+// The first convergent of the continued fraction, which is x as f64: 0.1 is 1/10
+fn continued_fraction(x: f64) -> Option<BigRational> {
+    // convergents p/q, and the previous ones
+    let (mut p, mut q) = (BigInt::one(), BigInt::zero());
+    let (mut p0, mut q0) = (BigInt::zero(), BigInt::one());
+    let mut y = x;
+    for _ in 0..100 {
+        let a = y.floor();
+        let ai = BigInt::from_f64(a)?;
+        (p, p0) = (&ai * &p + &p0, p);
+        (q, q0) = (&ai * &q + &q0, q);
+        let res = BigRational::new(p.clone(), q.clone());
+        if y == a || res.to_f64() == Some(x) {
+            return Some(res);
+        }
+        y = 1.0 / (y - a);
+    }
+    None
+}
+
+// Size of the dictionary, forget and marker go back to it
+#[derive(Debug, Clone, Copy)]
+pub struct Mark {
+    pub prog: usize,
+    pub names: usize,
+    pub deferred: usize,
+}
+
+// Address changes of a compaction: the removed code ranges
+#[derive(Debug)]
+pub struct Relocation {
+    removed: Vec<Range<usize>>,
+}
+
+impl Relocation {
+    pub fn addr(&self, addr: usize) -> usize {
+        let removed: usize = self
+            .removed
+            .iter()
+            .take_while(|r| r.end <= addr)
+            .map(|r| r.len())
+            .sum();
+        addr - removed
+    }
+
+    pub fn mark(&self, mark: Mark) -> Mark {
+        Mark {
+            prog: self.addr(mark.prog),
+            ..mark
+        }
+    }
+}
+
+// State at "catch", an error unwinds to it
+#[derive(Debug)]
+struct CatchFrame {
+    resume: usize,         // after the EndCatch
+    stack: Vec<StackType>, // a copy, the quotation may consume the cells below
+    ret_stack: usize,
+    loop_stack: usize,
+    times_stack: usize,
+    locals: usize,
+    frames: usize,
+}
+
 #[derive(Debug)]
 pub struct Runner {
     fractionaldigit: usize,
+    #[cfg(feature = "bigdecimal")]
+    precision: u64, // significant digits of the big decimals
+    radix: u32,     // of the integers on the output
+    word_bits: u32, // word size of the output and the bit operations
+    signed: bool,
+    exact: bool,     // "exactmode", the integer division is rational
+    cplx_mode: bool, // "cplxmode", complex result outside of the real domain
+    polar: bool,     // output of the complex numbers: magnitude@phase
+    degrees: bool,   // unit of the phase
     prog: Vec<Instruction>,
+    prog_pos: Vec<Option<SrcPos>>, // source position of the instructions, indexed by PC
+    defs: Vec<Range<usize>>,       // code of the subroutines
     pc: usize,
     stack: Vec<StackType>,
     ret_stack: Vec<usize>,
+    max_depth: usize,               // limit of the ret_stack
+    loop_stack: Vec<(f64, f64)>,    // index and limit of the counted loops
+    times_stack: Vec<(usize, f64)>, // quotation and remaining count of "times"
+    locals: Vec<StackType>,         // local variables of the active subroutine calls
+    frames: Vec<usize>,             // start of the locals of the subroutine calls
+    catch_stack: Vec<CatchFrame>,
     registers: [StackType; 256],
+    names: Vec<NamedSlot>,                  // named variables and constants
+    deferred: Vec<(String, Option<usize>)>, // deferred words and their subroutine
     vectors: Vec<VectorType>,
     verbose: bool,
     stopped: Arc<AtomicBool>,
+    quit: bool,
 }
 
 impl Runner {
     pub fn new(verbose: bool) -> Self {
-        let stopped = Arc::new(AtomicBool::new(false));
-        let r = stopped.clone();
-
-        ctrlc::set_handler(move || {
-            r.store(true, Ordering::SeqCst);
-        })
-        .expect("Error setting Ctrl-C handler");
-
         let mut vectors = Vec::new();
         for _ in 0..256 {
             vectors.push(VectorType {
@@ -48,140 +205,885 @@ impl Runner {
         }
         Runner {
             fractionaldigit: 0,
+            #[cfg(feature = "bigdecimal")]
+            precision: 100,
+            radix: 10,
+            word_bits: 64,
+            signed: true,
+            exact: false,
+            cplx_mode: false,
+            polar: false,
+            degrees: false,
             prog: vec![],
+            prog_pos: vec![],
+            defs: vec![],
             pc: 0,
             stack: Vec::new(),
             ret_stack: Vec::new(),
-            registers: [StackType::None; 256],
+            max_depth: MAX_RET_STACK,
+            loop_stack: Vec::new(),
+            times_stack: Vec::new(),
+            locals: Vec::new(),
+            frames: Vec::new(),
+            catch_stack: Vec::new(),
+            registers: std::array::from_fn(|_| StackType::None),
+            names: Vec::new(),
+            deferred: Vec::new(),
             vectors,
             verbose,
-            stopped,
+            stopped: Arc::new(AtomicBool::new(false)),
+            quit: false,
         }
     }
 
+    // Set this flag (e.g. from a Ctrl-C handler) to stop a running loop.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stopped.clone()
+    }
+
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    pub fn stack(&self) -> &[StackType] {
+        &self.stack
+    }
+
     pub fn get_proglen(&mut self) -> usize {
         self.prog.len()
     }
 
+    // new slot for a named variable or constant
+    pub fn add_name(&mut self, name: &str) -> usize {
+        self.names.push(NamedSlot {
+            name: name.to_string(),
+            constant: false,
+            value: StackType::None,
+        });
+        self.names.len() - 1
+    }
+
+    pub fn add_deferred(&mut self, name: &str) -> usize {
+        self.deferred.push((name.to_string(), None));
+        self.deferred.len() - 1
+    }
+
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    pub fn set_name_kind(&mut self, slot: usize, constant: bool) {
+        self.names[slot].constant = constant;
+    }
+
     // add procedure, without running. For procedures.
-    pub fn add_instr(&mut self, add_instr: &[Instruction]) {
-        for i in add_instr {
-            self.prog.push(*i);
+    pub fn add_instr(&mut self, add_instr: &[Instruction], add_pos: &[SrcPos]) {
+        let start = self.prog.len();
+        self.append(add_instr, add_pos);
+        self.defs.push(start..self.prog.len());
+        self.pc = self.prog.len();
+    }
+
+    pub fn mark(&self) -> Mark {
+        Mark {
+            prog: self.prog.len(),
+            names: self.names.len(),
+            deferred: self.deferred.len(),
         }
+    }
+
+    // Drop the subroutines, names and deferred words defined after the mark
+    pub fn forget(&mut self, mark: Mark) {
+        self.prog.truncate(mark.prog);
+        self.prog_pos.truncate(mark.prog);
+        self.defs.retain(|d| d.start < mark.prog);
         self.pc = self.prog.len();
+        self.names.truncate(mark.names);
+        self.deferred.truncate(mark.deferred);
+        for (_, addr) in &mut self.deferred {
+            if addr.is_some_and(|a| a >= mark.prog) {
+                *addr = None;
+            }
+        }
+        for v in self.values_mut() {
+            if matches!(*v, StackType::Quote(a) if a >= mark.prog) {
+                *v = StackType::None;
+            }
+        }
+    }
+
+    // Internal func, the stored values, they may be quotations
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut StackType> {
+        self.stack
+            .iter_mut()
+            .chain(self.locals.iter_mut())
+            .chain(self.registers.iter_mut())
+            .chain(self.names.iter_mut().map(|n| &mut n.value))
+    }
+
+    // Remove the subroutines not reachable from the roots (the current definitions),
+    // from the deferred words and from the quotations, and move the rest together.
+    pub fn compact(&mut self, roots: &[usize]) -> Relocation {
+        let mut live = vec![false; self.defs.len()];
+        let mut todo = roots.to_vec();
+        todo.extend(self.deferred.iter().filter_map(|d| d.1));
+        todo.extend(self.values_mut().filter_map(|v| match v {
+            StackType::Quote(addr) => Some(*addr),
+            _ => None,
+        }));
+        while let Some(addr) = todo.pop() {
+            // the quotations are inside of the code
+            let n = self.defs.partition_point(|d| d.start <= addr);
+            if n == 0 || !self.defs[n - 1].contains(&addr) || live[n - 1] {
+                continue;
+            }
+            live[n - 1] = true;
+            for instr in &self.prog[self.defs[n - 1].clone()] {
+                if let Instruction::Call(a)
+                | Instruction::SetDeferred(_, a)
+                | Instruction::Literal(StackType::Quote(a)) = instr
+                {
+                    todo.push(*a);
+                }
+            }
+        }
+        let reloc = Relocation {
+            removed: self
+                .defs
+                .iter()
+                .zip(&live)
+                .filter(|(_, live)| !**live)
+                .map(|(d, _)| d.clone())
+                .collect(),
+        };
+        if reloc.removed.is_empty() {
+            return reloc;
+        }
+
+        let mut prog = Vec::new();
+        let mut prog_pos = Vec::new();
+        let mut defs = Vec::new();
+        for (d, _) in self.defs.iter().zip(&live).filter(|(_, live)| **live) {
+            let start = prog.len();
+            for mut instr in self.prog[d.clone()].iter().cloned() {
+                if let Some(addr) = instr.addr_mut() {
+                    *addr = reloc.addr(*addr);
+                }
+                prog.push(instr);
+            }
+            prog_pos.extend_from_slice(&self.prog_pos[d.clone()]);
+            defs.push(start..prog.len());
+        }
+        for (_, addr) in &mut self.deferred {
+            if let Some(addr) = addr {
+                *addr = reloc.addr(*addr);
+            }
+        }
+        for v in self.values_mut() {
+            if let StackType::Quote(addr) = v {
+                *addr = reloc.addr(*addr);
+            }
+        }
+        self.prog = prog;
+        self.prog_pos = prog_pos;
+        self.defs = defs;
+        self.pc = self.prog.len();
+        reloc
+    }
+
+    // add_pos may be shorter (or empty) if the positions are unknown
+    fn append(&mut self, add_instr: &[Instruction], add_pos: &[SrcPos]) {
+        for (n, i) in add_instr.iter().enumerate() {
+            self.prog.push(i.clone());
+            self.prog_pos.push(add_pos.get(n).cloned());
+        }
+    }
+
+    // runtime error at the current PC, with the trace of the active subroutine calls
+    fn error(&self, kind: CalcError) -> Error {
+        Error {
+            kind,
+            instr: Some(Box::new(self.prog[self.pc].clone())),
+            pc: Some(self.pc),
+            pos: self.prog_pos[self.pc].clone().map(Box::new),
+            trace: self
+                .ret_stack
+                .iter()
+                .rev()
+                .filter_map(|&ret| self.prog_pos[ret].clone())
+                .collect(),
+        }
     }
 
     // Internal func
-    fn get_double(&mut self) -> Option<f64> {
+    fn get_double(&mut self) -> Result<f64, CalcError> {
         let Some(a) = self.stack.pop() else {
-            eprintln!("Stack is empty!");
-            return None;
+            return Err(CalcError::StackUnderflow);
+        };
+        match real(&a) {
+            Some(a) => Ok(a),
+            None => Err(CalcError::TypeMismatch {
+                expected: "real number",
+                found: Box::new(a),
+            }),
+        }
+    }
+
+    // Internal func, a real number is complex with 0 imaginary part
+    fn get_complex(&mut self) -> Result<Complex<f64>, CalcError> {
+        let Some(a) = self.stack.pop() else {
+            return Err(CalcError::StackUnderflow);
+        };
+        match complex(&a) {
+            Some(a) => Ok(a),
+            None => Err(CalcError::TypeMismatch {
+                expected: "number",
+                found: Box::new(a),
+            }),
+        }
+    }
+
+    // Internal func, 64 bits of an integer, or of a real number without fraction
+    fn get_int(&mut self) -> Result<i64, CalcError> {
+        let Some(a) = self.stack.pop() else {
+            return Err(CalcError::StackUnderflow);
+        };
+        match a {
+            StackType::Int(a) => Ok(a),
+            StackType::Double(d)
+                if d.fract() == 0.0 && d >= -(2f64.powi(63)) && d < 2f64.powi(64) =>
+            {
+                Ok(if d < 0.0 { d as i64 } else { d as u64 as i64 })
+            }
+            _ => Err(CalcError::TypeMismatch {
+                expected: "integer",
+                found: Box::new(a),
+            }),
+        }
+    }
+
+    // Internal func, the value of an integer for the arithmetic, not wrapped to 64 bits
+    fn get_signed(&mut self) -> Result<i64, CalcError> {
+        let Some(a) = self.stack.pop() else {
+            return Err(CalcError::StackUnderflow);
+        };
+        match a {
+            StackType::Int(a) => Ok(a),
+            StackType::Double(d) if d.fract() == 0.0 => {
+                if d >= -(2f64.powi(63)) && d < 2f64.powi(63) {
+                    Ok(d as i64)
+                } else {
+                    Err(CalcError::OutOfRange)
+                }
+            }
+            StackType::Rational(r) if r.is_integer() => Err(CalcError::OutOfRange),
+            _ => Err(CalcError::TypeMismatch {
+                expected: "integer",
+                found: Box::new(a),
+            }),
+        }
+    }
+
+    // Internal func
+    fn get_string(&mut self) -> Result<String, CalcError> {
+        let Some(a) = self.stack.pop() else {
+            return Err(CalcError::StackUnderflow);
+        };
+        let StackType::Str(a) = a else {
+            return Err(CalcError::TypeMismatch {
+                expected: "string",
+                found: Box::new(a),
+            });
+        };
+        Ok(a)
+    }
+
+    // Internal func
+    fn get_quote(&mut self) -> Result<usize, CalcError> {
+        let Some(a) = self.stack.pop() else {
+            return Err(CalcError::StackUnderflow);
         };
-        let StackType::Double(a) = a else {
-            eprintln!("Get double: type error (Complex)");
+        let StackType::Quote(addr) = a else {
+            return Err(CalcError::TypeMismatch {
+                expected: "quotation",
+                found: Box::new(a),
+            });
+        };
+        Ok(addr)
+    }
+
+    // Internal func, the two strings on the top of the stack
+    fn get_strings(&mut self) -> Option<(String, String)> {
+        let [.., StackType::Str(_), StackType::Str(_)] = self.stack[..] else {
             return None;
         };
-        Some(a)
+        let (Some(StackType::Str(a)), Some(StackType::Str(b))) =
+            (self.stack.pop(), self.stack.pop())
+        else {
+            unreachable!()
+        };
+        Some((a, b))
+    }
+
+    // Internal func, order of two real numbers or two strings, for the relations
+    fn get_ordering(&mut self) -> Result<Option<std::cmp::Ordering>, CalcError> {
+        if let Some((a, b)) = self.get_strings() {
+            return Ok(Some(b.cmp(&a)));
+        }
+        if let [.., StackType::Int(b), StackType::Int(a)] = self.stack[..] {
+            self.stack.truncate(self.stack.len() - 2);
+            return Ok(Some(b.cmp(&a)));
+        }
+        if let [.., b, a] = &self.stack[..] {
+            if let (Some(b), Some(a)) = (to_rational(b), to_rational(a)) {
+                self.stack.truncate(self.stack.len() - 2);
+                return Ok(Some(b.cmp(&a)));
+            }
+        }
+        let a = self.get_double()?;
+        let b = self.get_double()?;
+        Ok(b.partial_cmp(&a))
+    }
+
+    // Internal func, the integer truncated to the word size, sign or zero extended
+    fn wrap(&self, a: i64) -> i64 {
+        let shift = 64 - self.word_bits;
+        if self.signed {
+            (a << shift) >> shift
+        } else {
+            ((a as u64) << shift >> shift) as i64
+        }
+    }
+
+    // Internal func, bits of the word size
+    fn word_mask(&self) -> u64 {
+        u64::MAX >> (64 - self.word_bits)
+    }
+
+    // Internal func, text of an integer in the current radix,
+    // the bits of the word size in hex, oct and bin, the value in decimal
+    fn format_int(&self, a: i64) -> String {
+        let bits = self.wrap(a) as u64 & self.word_mask();
+        match self.radix {
+            16 => format!("0x{bits:X}"),
+            8 => format!("0o{bits:o}"),
+            2 => format!("0b{bits:b}"),
+            _ => format!("{a}"),
+        }
+    }
+
+    // Internal func, text of a number for print and >str
+    fn format(&self, a: &StackType) -> String {
+        match a {
+            // in hex, oct and bin the whole numbers are shown as integers
+            StackType::Double(res)
+                if self.radix != 10 && res.fract() == 0.0 && res.abs() < 2f64.powi(63) =>
+            {
+                self.format_int(*res as i64)
+            }
+            StackType::Double(res) => {
+                if self.fractionaldigit > 0 {
+                    format!("{res:.*?}", self.fractionaldigit)
+                } else {
+                    format!("{res:?}")
+                }
+            }
+            StackType::Int(res) => self.format_int(*res),
+            StackType::Rational(res) => res.to_string(),
+            #[cfg(feature = "bigdecimal")]
+            StackType::Big(res) => {
+                if self.fractionaldigit > 0 {
+                    let res =
+                        res.with_scale_round(self.fractionaldigit as i64, RoundingMode::HalfEven);
+                    res.to_string()
+                } else {
+                    res.to_string()
+                }
+            }
+            StackType::Complex(res) if self.polar => {
+                let (mag, phase) = res.to_polar();
+                let (phase, unit) = if self.degrees {
+                    // rounded, 1@60d shows 60.0, not the 59.99999999999999 of the conversions
+                    ((phase.to_degrees() * 1e12).round() / 1e12, "d")
+                } else {
+                    (phase, "")
+                };
+                if self.fractionaldigit > 0 {
+                    let digits = self.fractionaldigit;
+                    format!("{mag:.digits$?}@{phase:.digits$?}{unit}")
+                } else {
+                    format!("{mag:?}@{phase:?}{unit}")
+                }
+            }
+            StackType::Complex(res) => {
+                if self.fractionaldigit > 0 {
+                    format!("{res:.*?}", self.fractionaldigit)
+                } else {
+                    format!("{res:?}")
+                }
+            }
+            StackType::Str(s) => s.clone(),
+            StackType::Quote(addr) => format!("{{ quotation at {addr} }}"),
+            StackType::None => String::new(),
+        }
+    }
+
+    // Internal func, subroutine call
+    fn call(&mut self, addr: usize) -> Result<(), CalcError> {
+        if self.ret_stack.len() >= self.max_depth {
+            return Err(CalcError::ReturnStackOverflow);
+        }
+        self.ret_stack.push(self.pc);
+        self.pc = addr;
+        Ok(())
+    }
+
+    // Internal func, Ctrl-C check in the loops
+    fn check_stopped(&self) -> Result<(), CalcError> {
+        if self.stopped.load(Ordering::SeqCst) {
+            self.stopped.store(false, Ordering::SeqCst);
+            return Err(CalcError::Interrupted);
+        }
+        Ok(())
+    }
+
+    // Internal func, index of an element of a real or complex vector
+    fn get_index(&mut self, regnum: u8) -> Result<usize, CalcError> {
+        let a = self.get_double()?;
+        let v = &self.vectors[regnum as usize];
+        let len = match v.data_type {
+            Type::Double => v.vector.len(),
+            Type::Complex => v.vector.len() / 2,
+        };
+        if !(a >= 0.0 && (a as usize) < len) {
+            return Err(CalcError::IndexOutOfRange {
+                vector: regnum,
+                index: a,
+                len,
+            });
+        }
+        Ok(a as usize)
     }
 
-    // Internal func, return: Real:Real or Complex:Complex from any pair
-    fn get_samenum(&mut self) -> Option<(StackType, StackType)> {
+    // Internal func, length of a new vector
+    fn get_length(&mut self) -> Result<usize, CalcError> {
+        let a = self.get_double()?;
+        if !(a >= 0.0 && a <= MAX_VECTOR as f64) {
+            return Err(CalcError::InvalidLength(a));
+        }
+        Ok(a as usize)
+    }
+
+    // Internal func, return: Int:Int, Real:Real or Complex:Complex from any pair
+    fn get_samenum(&mut self) -> Result<(StackType, StackType), CalcError> {
         let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) else {
-            eprintln!("Stack empty!");
-            return None;
+            return Err(CalcError::StackUnderflow);
+        };
+        if let (StackType::Int(_), StackType::Int(_)) = (&a, &b) {
+            if !self.exact {
+                return Ok((a, b));
+            }
+        }
+        // Rational with Int, or two Int in exact mode
+        if let (Some(ra), Some(rb)) = (to_rational(&a), to_rational(&b)) {
+            Ok((StackType::Rational(ra), StackType::Rational(rb)))
+        } else if let (Some(da), Some(db)) = (real(&a), real(&b)) {
+            Ok((StackType::Double(da), StackType::Double(db)))
+        } else if let (Some(ca), Some(cb)) = (complex(&a), complex(&b)) {
+            Ok((StackType::Complex(ca), StackType::Complex(cb)))
+        } else {
+            Err(CalcError::TypeMismatch {
+                expected: "number",
+                found: Box::new(if complex(&a).is_some() { b } else { a }),
+            })
+        }
+    }
+
+    // Internal func, exact n!, nCr (Ncr) or nPr (Npr) of integers, big integer in "exactmode"
+    fn exact_count(&self, ncr: bool, n: i64, r: i64) -> Result<StackType, CalcError> {
+        if n < 0 || r < 0 {
+            return Err(CalcError::BadArgument);
+        }
+        if r > n {
+            return Ok(StackType::Int(0));
+        }
+        let r = if ncr { r.min(n - r) } else { r };
+        if r > MAX_FACTORS {
+            return Err(CalcError::OutOfRange);
+        }
+        let mut res = special::product((n - r + 1) as u64, n as u64);
+        if ncr {
+            res /= special::product(1, r as u64);
+        }
+        if !self.exact && res.to_i64().is_none() {
+            return Err(CalcError::IntegerOverflow);
+        }
+        Ok(rational(BigRational::from_integer(res)))
+    }
+
+    // Internal func, function of a real or complex number. Outside of the real domain
+    // the result is NaN, or complex in "cplxmode".
+    fn unary_fn(
+        &mut self,
+        fr: impl Fn(f64) -> f64,
+        fc: impl Fn(Complex<f64>) -> Complex<f64>,
+        domain: impl Fn(f64) -> bool,
+    ) -> Result<(), CalcError> {
+        let Some(a) = self.stack.pop() else {
+            return Err(CalcError::StackUnderflow);
+        };
+        let res = match (real(&a), complex(&a)) {
+            (Some(x), _) if !self.cplx_mode || domain(x) => StackType::Double(fr(x)),
+            (_, Some(c)) => StackType::Complex(fc(c)),
+            _ => {
+                return Err(CalcError::TypeMismatch {
+                    expected: "number",
+                    found: Box::new(a),
+                })
+            }
+        };
+        self.stack.push(res);
+        Ok(())
+    }
+
+    // Internal func, function of two real or complex numbers, b is the second: f(b, a)
+    fn binary_fn(
+        &mut self,
+        fr: impl Fn(f64, f64) -> f64,
+        fc: impl Fn(Complex<f64>, Complex<f64>) -> Complex<f64>,
+        domain: impl Fn(f64, f64) -> bool,
+    ) -> Result<(), CalcError> {
+        let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) else {
+            return Err(CalcError::StackUnderflow);
+        };
+        let res = match ((real(&b), real(&a)), (complex(&b), complex(&a))) {
+            ((Some(y), Some(x)), _) if !self.cplx_mode || domain(y, x) => {
+                StackType::Double(fr(y, x))
+            }
+            (_, (Some(cb), Some(ca))) => StackType::Complex(fc(cb, ca)),
+            _ => {
+                return Err(CalcError::TypeMismatch {
+                    expected: "number",
+                    found: Box::new(if complex(&a).is_some() { b } else { a }),
+                })
+            }
         };
-        if let (StackType::Double(da), StackType::Double(db)) = (a, b) {
-            Some((StackType::Double(da), StackType::Double(db)))
-        } else if let (StackType::Complex(da), StackType::Complex(db)) = (a, b) {
-            Some((StackType::Complex(da), StackType::Complex(db)))
-        } else if let (StackType::Double(da), StackType::Complex(db)) = (a, b) {
-            Some((
-                StackType::Complex(Complex::new(da, 0.0)),
-                StackType::Complex(db),
-            ))
-        } else if let (StackType::Complex(da), StackType::Double(db)) = (a, b) {
-            Some((
-                StackType::Complex(da),
-                StackType::Complex(Complex::new(db, 0.0)),
-            ))
+        self.stack.push(res);
+        Ok(())
+    }
+
+    pub fn run(&mut self, add_instr: &[Instruction], add_pos: &[SrcPos]) -> Result<(), Error> {
+        let start = self.prog.len();
+        self.append(add_instr, add_pos);
+        let res = loop {
+            match self.exec() {
+                // Ctrl-C can't be caught
+                Err(kind) if kind != CalcError::Interrupted && !self.catch_stack.is_empty() => {
+                    self.unwind(kind)
+                }
+                res => break res.map_err(|kind| self.error(kind)),
+            }
+        };
+        // if breaked, drop the remaining part of the program
+        if self.pc < self.prog.len() {
+            self.pc = self.prog.len();
+            self.ret_stack.clear();
+            self.loop_stack.clear();
+            self.times_stack.clear();
+            self.locals.clear();
+            self.frames.clear();
+            self.catch_stack.clear();
+        }
+        if add_instr
+            .iter()
+            .any(|i| matches!(i, Instruction::Literal(StackType::Quote(_))))
+        {
+            // the quotations may be called later, compact() reclaims it
+            self.defs.push(start..self.prog.len());
         } else {
-            eprintln!("Not a number!");
-            None
+            // the top-level code is not needed any more
+            self.prog.truncate(start);
+            self.prog_pos.truncate(start);
+            self.pc = start;
         }
+        res
+    }
+
+    // Back to the innermost catch, with the error code on the stack.
+    // The stack is restored to its state at the catch.
+    fn unwind(&mut self, kind: CalcError) {
+        let Some(frame) = self.catch_stack.pop() else {
+            return;
+        };
+        self.stack = frame.stack;
+        self.ret_stack.truncate(frame.ret_stack);
+        self.loop_stack.truncate(frame.loop_stack);
+        self.times_stack.truncate(frame.times_stack);
+        self.locals.truncate(frame.locals);
+        self.frames.truncate(frame.frames);
+        self.stack.push(StackType::Double(kind.code() as f64));
+        self.pc = frame.resume;
     }
 
-    pub fn run(&mut self, add_instr: &[Instruction]) {
-        for i in add_instr {
-            self.prog.push(*i);
+    // Internal func, the instruction with big decimal operands ("N precision"),
+    // false if the operands are not big decimals.
+    #[cfg(feature = "bigdecimal")]
+    fn exec_big(&mut self) -> Result<bool, CalcError> {
+        let instr = &self.prog[self.pc];
+        let big = |a: &StackType| matches!(a, StackType::Big(_));
+        let res = match &self.stack[..] {
+            [.., b, a] if big(a) || big(b) => match (bigmath::from(b), bigmath::from(a)) {
+                (Some(b), Some(a)) => bigmath::binary(instr, &b, &a, self.precision),
+                _ => None,
+            },
+            _ => None,
+        }
+        .map(|res| (2, res));
+        let res = res.or_else(|| match self.stack.last() {
+            Some(StackType::Big(a)) => {
+                bigmath::unary(instr, a, self.precision).map(|res| (1, res.map(StackType::Big)))
+            }
+            _ => None,
+        });
+        let Some((n, res)) = res else {
+            return Ok(false);
+        };
+        // outside of the real domain, the complex functions of f64 in "cplxmode"
+        if self.cplx_mode && res == Err(CalcError::BadArgument) {
+            return Ok(false);
+        }
+        let res = res?;
+        // limited as the literals, else dup * grows the digits without end
+        if let StackType::Big(r) = &res {
+            if r.order_of_magnitude().unsigned_abs() > MAX_EXACT_EXP {
+                return Err(CalcError::OutOfRange);
+            }
         }
+        self.stack.truncate(self.stack.len() - n);
+        self.stack.push(res);
+        Ok(true)
+    }
+
+    fn exec(&mut self) -> Result<(), CalcError> {
         while self.pc < self.prog.len() {
             if self.verbose {
                 println!("Debug: PC: {} Instr: {:?}", self.pc, self.prog[self.pc]);
             }
-            match self.prog[self.pc] {
+            #[cfg(feature = "bigdecimal")]
+            if self.exec_big()? {
+                self.pc += 1;
+                continue;
+            }
+            match self.prog[self.pc].clone() {
                 Instruction::Literal(lit) => {
                     self.stack.push(lit);
                     if self.stack.len() >= MAX_STACK {
-                        eprintln!(
-                            "Stack is FULL ({} element)! Please clear it.",
-                            self.stack.len()
-                        );
-                        break;
+                        return Err(CalcError::StackOverflow);
                     }
                 }
                 Instruction::Call(addr) => {
-                    self.ret_stack.push(self.pc);
-                    self.pc = addr;
+                    self.call(addr)?;
                     continue; // don't increment PC
                 }
+                Instruction::CallDeferred(slot) => {
+                    let (name, addr) = &self.deferred[slot];
+                    let Some(addr) = *addr else {
+                        return Err(CalcError::UnsetDeferred(name.clone()));
+                    };
+                    self.call(addr)?;
+                    continue;
+                }
+                Instruction::SetDeferred(slot, addr) => {
+                    self.deferred[slot].1 = Some(addr);
+                }
                 Instruction::Ret => {
                     let Some(pc) = self.ret_stack.pop() else {
-                        eprintln!("Return stack is empty!");
-                        break;
+                        return Err(CalcError::ReturnStackUnderflow);
                     };
                     self.pc = pc;
                 }
+                Instruction::Locals(n) => {
+                    let Some(start) = self.stack.len().checked_sub(n) else {
+                        return Err(CalcError::StackUnderflow);
+                    };
+                    self.frames.push(self.locals.len());
+                    self.locals.extend(self.stack.drain(start..));
+                }
+                Instruction::EndLocals => {
+                    let Some(base) = self.frames.pop() else {
+                        return Err(CalcError::ReturnStackUnderflow);
+                    };
+                    self.locals.truncate(base);
+                }
+                Instruction::LocalGet(idx) => {
+                    let local = self
+                        .frames
+                        .last()
+                        .and_then(|&base| self.locals.get(base + idx));
+                    let Some(local) = local else {
+                        return Err(CalcError::ReturnStackUnderflow);
+                    };
+                    self.stack.push(local.clone());
+                    if self.stack.len() >= MAX_STACK {
+                        return Err(CalcError::StackOverflow);
+                    }
+                }
                 Instruction::Jnz(addr) => {
                     let Some(a) = self.stack.pop() else {
-                        eprintln!("Stack is empty!");
-                        break;
-                    };
-                    if self.stopped.load(Ordering::SeqCst) {
-                        self.stopped.store(false, Ordering::SeqCst);
-                        eprintln!("Ctrl-C ... stop");
-                        break;
-                    } else if a != StackType::Double(0.0) {
+                        return Err(CalcError::StackUnderflow);
+                    };
+                    self.check_stopped()?;
+                    if !is_zero(&a) {
+                        self.pc = addr;
+                        continue;
+                    }
+                }
+                Instruction::Jz(addr) => {
+                    let Some(a) = self.stack.pop() else {
+                        return Err(CalcError::StackUnderflow);
+                    };
+                    if is_zero(&a) {
+                        if addr <= self.pc {
+                            self.check_stopped()?; // "until"
+                        }
+                        self.pc = addr;
+                        continue;
+                    }
+                }
+                Instruction::Jmp(addr) => {
+                    if addr <= self.pc {
+                        self.check_stopped()?; // "repeat"
+                    }
+                    self.pc = addr;
+                    continue;
+                }
+                Instruction::CallQuote => {
+                    let addr = self.get_quote()?;
+                    self.call(addr)?;
+                    continue;
+                }
+                Instruction::Times => {
+                    let addr = self.get_quote()?;
+                    let n = self.get_double()?;
+                    self.times_stack.push((addr, n));
+                }
+                Instruction::TimesStep => {
+                    self.check_stopped()?;
+                    let Some((addr, n)) = self.times_stack.last_mut() else {
+                        return Err(CalcError::NoLoop);
+                    };
+                    if *n >= 1.0 {
+                        *n -= 1.0;
+                        let addr = *addr;
+                        // return to this instruction
+                        self.pc -= 1;
+                        self.call(addr)?;
+                        continue;
+                    }
+                    self.times_stack.pop();
+                }
+                Instruction::Ifte => {
+                    let else_addr = self.get_quote()?;
+                    let then_addr = self.get_quote()?;
+                    let cond = self.get_double()?;
+                    self.call(if cond != 0.0 { then_addr } else { else_addr })?;
+                    continue;
+                }
+                Instruction::Catch => {
+                    let addr = self.get_quote()?;
+                    self.catch_stack.push(CatchFrame {
+                        resume: self.pc + 2,
+                        stack: self.stack.clone(),
+                        ret_stack: self.ret_stack.len(),
+                        loop_stack: self.loop_stack.len(),
+                        times_stack: self.times_stack.len(),
+                        locals: self.locals.len(),
+                        frames: self.frames.len(),
+                    });
+                    self.call(addr)?;
+                    continue;
+                }
+                Instruction::EndCatch => {
+                    self.catch_stack.pop();
+                    self.stack.push(StackType::Double(0.0));
+                }
+                Instruction::Throw => {
+                    let n = self.get_double()?;
+                    if n != 0.0 {
+                        return Err(CalcError::Throw(n as i64));
+                    }
+                }
+                Instruction::Do => {
+                    let start = self.get_double()?;
+                    let limit = self.get_double()?;
+                    self.loop_stack.push((start, limit));
+                }
+                Instruction::Loop(addr) => {
+                    self.check_stopped()?;
+                    let Some((index, limit)) = self.loop_stack.last_mut() else {
+                        return Err(CalcError::NoLoop);
+                    };
+                    *index += 1.0;
+                    if *index < *limit {
                         self.pc = addr;
+                        continue;
+                    }
+                    self.loop_stack.pop();
+                }
+                Instruction::PlusLoop(addr) => {
+                    self.check_stopped()?;
+                    let step = self.get_double()?;
+                    let Some((index, limit)) = self.loop_stack.last_mut() else {
+                        return Err(CalcError::NoLoop);
+                    };
+                    *index += step;
+                    // a negative step counts down to the limit (inclusive)
+                    if (step >= 0.0 && *index < *limit) || (step < 0.0 && *index >= *limit) {
+                        self.pc = addr;
+                        continue;
+                    }
+                    self.loop_stack.pop();
+                }
+                Instruction::I | Instruction::J => {
+                    let depth = if let Instruction::I = self.prog[self.pc] {
+                        1
+                    } else {
+                        2
+                    };
+                    let Some(&(index, _)) = self
+                        .loop_stack
+                        .len()
+                        .checked_sub(depth)
+                        .map(|i| &self.loop_stack[i])
+                    else {
+                        return Err(CalcError::NoLoop);
+                    };
+                    self.stack.push(StackType::Double(index));
+                    if self.stack.len() >= MAX_STACK {
+                        return Err(CalcError::StackOverflow);
                     }
                 }
 
                 // Stack operations
                 Instruction::Dup => {
                     let Some(a) = self.stack.last() else {
-                        eprintln!("Stack is empty!");
-                        break;
+                        return Err(CalcError::StackUnderflow);
                     };
-                    self.stack.push(*a);
+                    self.stack.push(a.clone());
                     if self.stack.len() >= MAX_STACK {
-                        eprintln!(
-                            "Stack is FULL ({} element)! Please clear it.",
-                            self.stack.len()
-                        );
-                        break;
+                        return Err(CalcError::StackOverflow);
                     }
                 }
                 Instruction::Drop => {
                     if self.stack.pop().is_none() {
-                        eprintln!("Stack is empty!");
-                        break;
+                        return Err(CalcError::StackUnderflow);
                     }
                 }
                 Instruction::Over => {
-                    let Some(&a) = self.stack.get(self.stack.len() - 2) else {
-                        eprintln!("Stack is empty!");
-                        break;
+                    let Some(a) = self.stack.len().checked_sub(2).map(|i| &self.stack[i]) else {
+                        return Err(CalcError::StackUnderflow);
                     };
-                    self.stack.push(a);
+                    self.stack.push(a.clone());
                 }
                 Instruction::Rot => {
                     if let (Some(a), Some(b), Some(c)) =
@@ -191,8 +1093,7 @@ impl Runner {
                         self.stack.push(a);
                         self.stack.push(c);
                     } else {
-                        eprintln!("Stack is empty!");
-                        break;
+                        return Err(CalcError::StackUnderflow);
                     }
                 }
                 Instruction::Swap => {
@@ -200,296 +1101,640 @@ impl Runner {
                         self.stack.push(a);
                         self.stack.push(b);
                     } else {
-                        eprintln!("Stack is empty!");
-                        break;
+                        return Err(CalcError::StackUnderflow);
                     }
                 }
                 Instruction::Clear => {
                     self.stack.clear();
                 }
                 Instruction::DumpStack => {
-                    println!("Stack: {:?}", self.stack);
+                    let items: Vec<String> = self
+                        .stack
+                        .iter()
+                        .map(|a| match a {
+                            StackType::Int(a) => format!("Int({})", self.format_int(*a)),
+                            StackType::Rational(a) => format!("Rational({a})"),
+                            StackType::Complex(_) if self.polar => {
+                                format!("Complex({})", self.format(a))
+                            }
+                            a => format!("{a:?}"),
+                        })
+                        .collect();
+                    println!("Stack: [{}]", items.join(", "));
                 }
 
                 // Basic arithmetic
                 Instruction::Add => {
-                    let Some((a, b)) = self.get_samenum() else {
-                        break;
-                    };
-                    if let (StackType::Double(a), StackType::Double(b)) = (a, b) {
-                        self.stack.push(StackType::Double(b + a));
-                    } else if let (StackType::Complex(a), StackType::Complex(b)) = (a, b) {
-                        self.stack.push(StackType::Complex(b + a));
+                    if let Some((a, b)) = self.get_strings() {
+                        // concatenation
+                        self.stack.push(StackType::Str(b + &a));
+                    } else {
+                        let (a, b) = self.get_samenum()?;
+                        if let (StackType::Int(a), StackType::Int(b)) = (&a, &b) {
+                            let Some(res) = b.checked_add(*a) else {
+                                return Err(CalcError::IntegerOverflow);
+                            };
+                            self.stack.push(StackType::Int(res));
+                        } else if let (StackType::Double(a), StackType::Double(b)) = (&a, &b) {
+                            self.stack.push(StackType::Double(b + a));
+                        } else if let (StackType::Rational(a), StackType::Rational(b)) = (&a, &b) {
+                            self.stack.push(rational(b + a));
+                        } else if let (StackType::Complex(a), StackType::Complex(b)) = (&a, &b) {
+                            self.stack.push(StackType::Complex(b + a));
+                        }
                     }
                 }
                 Instruction::Sub => {
-                    let Some((a, b)) = self.get_samenum() else {
-                        break;
-                    };
-                    if let (StackType::Double(a), StackType::Double(b)) = (a, b) {
+                    let (a, b) = self.get_samenum()?;
+                    if let (StackType::Int(a), StackType::Int(b)) = (&a, &b) {
+                        let Some(res) = b.checked_sub(*a) else {
+                            return Err(CalcError::IntegerOverflow);
+                        };
+                        self.stack.push(StackType::Int(res));
+                    } else if let (StackType::Double(a), StackType::Double(b)) = (&a, &b) {
                         self.stack.push(StackType::Double(b - a));
-                    } else if let (StackType::Complex(a), StackType::Complex(b)) = (a, b) {
+                    } else if let (StackType::Rational(a), StackType::Rational(b)) = (&a, &b) {
+                        self.stack.push(rational(b - a));
+                    } else if let (StackType::Complex(a), StackType::Complex(b)) = (&a, &b) {
                         self.stack.push(StackType::Complex(b - a));
                     }
                 }
                 Instruction::Mul => {
-                    let Some((a, b)) = self.get_samenum() else {
-                        break;
-                    };
-                    if let (StackType::Double(a), StackType::Double(b)) = (a, b) {
+                    let (a, b) = self.get_samenum()?;
+                    if let (StackType::Int(a), StackType::Int(b)) = (&a, &b) {
+                        let Some(res) = b.checked_mul(*a) else {
+                            return Err(CalcError::IntegerOverflow);
+                        };
+                        self.stack.push(StackType::Int(res));
+                    } else if let (StackType::Double(a), StackType::Double(b)) = (&a, &b) {
                         self.stack.push(StackType::Double(b * a));
-                    } else if let (StackType::Complex(a), StackType::Complex(b)) = (a, b) {
+                    } else if let (StackType::Rational(a), StackType::Rational(b)) = (&a, &b) {
+                        self.stack.push(rational(b * a));
+                    } else if let (StackType::Complex(a), StackType::Complex(b)) = (&a, &b) {
                         self.stack.push(StackType::Complex(b * a));
                     }
                 }
                 Instruction::Div => {
-                    let Some((a, b)) = self.get_samenum() else {
-                        break;
-                    };
-                    if let (StackType::Double(a), StackType::Double(b)) = (a, b) {
+                    let (a, b) = self.get_samenum()?;
+                    if is_zero(&a) {
+                        return Err(CalcError::DivisionByZero);
+                    }
+                    if let (StackType::Int(a), StackType::Int(b)) = (&a, &b) {
+                        // truncated, i64::MIN / -1 overflows
+                        let Some(res) = b.checked_div(*a) else {
+                            return Err(CalcError::IntegerOverflow);
+                        };
+                        self.stack.push(StackType::Int(res));
+                    } else if let (StackType::Double(a), StackType::Double(b)) = (&a, &b) {
                         self.stack.push(StackType::Double(b / a));
-                    } else if let (StackType::Complex(a), StackType::Complex(b)) = (a, b) {
+                    } else if let (StackType::Rational(a), StackType::Rational(b)) = (&a, &b) {
+                        self.stack.push(rational(b / a));
+                    } else if let (StackType::Complex(a), StackType::Complex(b)) = (&a, &b) {
                         self.stack.push(StackType::Complex(b / a));
                     }
                 }
+                // Bitwise, on 64 bits, the result is an integer
                 Instruction::And => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack
-                        .push(StackType::Double((b as u32 & a as u32) as f64));
+                    let a = self.get_int()?;
+                    let b = self.get_int()?;
+                    self.stack.push(StackType::Int(self.wrap(b & a)));
                 }
                 Instruction::Or => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack
-                        .push(StackType::Double((b as u32 | a as u32) as f64));
+                    let a = self.get_int()?;
+                    let b = self.get_int()?;
+                    self.stack.push(StackType::Int(self.wrap(b | a)));
                 }
                 Instruction::Xor => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack
-                        .push(StackType::Double((b as u32 ^ a as u32) as f64));
+                    let a = self.get_int()?;
+                    let b = self.get_int()?;
+                    self.stack.push(StackType::Int(self.wrap(b ^ a)));
                 }
                 Instruction::Neg => {
-                    let Some(a) = self.get_double() else { break };
+                    let a = self.get_int()?;
+                    self.stack.push(StackType::Int(self.wrap(!a)));
+                }
+                Instruction::Shl | Instruction::Shr => {
+                    let a = self.get_int()?;
+                    let b = self.get_int()? as u64 & self.word_mask();
+                    // logical shift, the word size or more (or negative) gives 0
+                    let res = u32::try_from(a).ok().and_then(|a| {
+                        if let Instruction::Shl = self.prog[self.pc] {
+                            b.checked_shl(a)
+                        } else {
+                            b.checked_shr(a)
+                        }
+                    });
                     self.stack
-                        .push(StackType::Double((a as u32 ^ 0xffff_ffff) as f64));
+                        .push(StackType::Int(self.wrap(res.unwrap_or(0) as i64)));
+                }
+                Instruction::Rol | Instruction::Ror => {
+                    let bits = self.word_bits;
+                    let a = self.get_int()?.rem_euclid(bits as i64) as u32;
+                    let b = self.get_int()? as u64 & self.word_mask();
+                    // rotate in the word size
+                    let a = if let Instruction::Rol = self.prog[self.pc] {
+                        a
+                    } else {
+                        (bits - a) % bits
+                    };
+                    let res = b << a | b.checked_shr(bits - a).unwrap_or(0);
+                    self.stack.push(StackType::Int(self.wrap(res as i64)));
+                }
+                Instruction::Popcount => {
+                    let a = self.get_int()? as u64 & self.word_mask();
+                    self.stack.push(StackType::Int(a.count_ones() as i64));
+                }
+                Instruction::Clz => {
+                    let a = self.get_int()? as u64 & self.word_mask();
+                    let zeros = a.leading_zeros() - (64 - self.word_bits);
+                    self.stack.push(StackType::Int(zeros as i64));
+                }
+                Instruction::ToInt => {
+                    let a = self.get_double()?;
+                    // the conversion saturates, i64::MAX as f64 is already out of range
+                    if !(a.trunc() >= i64::MIN as f64 && a.trunc() < i64::MAX as f64) {
+                        return Err(CalcError::IntegerOverflow);
+                    }
+                    self.stack.push(StackType::Int(a as i64));
                 }
-                Instruction::Shl => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack
-                        .push(StackType::Double(((b as u32) << a as u32) as f64));
+                Instruction::ToFloat => {
+                    let a = self.get_double()?;
+                    self.stack.push(StackType::Double(a));
                 }
-                Instruction::Shr => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack
-                        .push(StackType::Double(((b as u32) >> a as u32) as f64));
+                Instruction::Exact(exact) => self.exact = exact,
+                Instruction::ToFrac => {
+                    // Int and Rational are exact already
+                    if !matches!(
+                        self.stack.last(),
+                        Some(StackType::Int(_) | StackType::Rational(_))
+                    ) {
+                        let a = self.get_double()?;
+                        let Some(res) = continued_fraction(a) else {
+                            return Err(CalcError::BadArgument);
+                        };
+                        self.stack.push(rational(res));
+                    }
                 }
+
                 Instruction::Abs => {
-                    let Some(a) = self.stack.pop() else { break };
+                    let Some(a) = self.stack.pop() else {
+                        return Err(CalcError::StackUnderflow);
+                    };
                     if let StackType::Double(a) = a {
                         self.stack.push(StackType::Double(a.abs()));
+                    } else if let StackType::Int(a) = a {
+                        let Some(a) = a.checked_abs() else {
+                            return Err(CalcError::IntegerOverflow);
+                        };
+                        self.stack.push(StackType::Int(a));
+                    } else if let StackType::Rational(a) = a {
+                        self.stack.push(StackType::Rational(a.abs()));
                     } else if let StackType::Complex(a) = a {
                         self.stack.push(StackType::Double(a.norm()));
                     }
                 }
-                Instruction::Floor => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.floor()));
-                }
-                Instruction::Ceil => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.ceil()));
-                }
-                Instruction::Round => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.round()));
-                }
-
-                // Trigonometric function
-                Instruction::CosR => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.cos()));
-                }
-                Instruction::SinR => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.sin()));
+                Instruction::Sqrt => self.unary_fn(f64::sqrt, Complex::sqrt, |a| a >= 0.0)?,
+                Instruction::Cbrt => self.unary_fn(f64::cbrt, Complex::cbrt, |_| true)?,
+                Instruction::Sign => {
+                    let Some(a) = self.stack.pop() else {
+                        return Err(CalcError::StackUnderflow);
+                    };
+                    let res = match (&a, real(&a)) {
+                        (StackType::Int(a), _) => StackType::Int(a.signum()),
+                        (StackType::Rational(a), _) => rational(a.signum()),
+                        (StackType::Complex(a), _) if a.norm() > 0.0 => {
+                            StackType::Complex(a.unscale(a.norm()))
+                        }
+                        (StackType::Complex(a), _) => StackType::Complex(*a),
+                        (_, Some(0.0)) => StackType::Double(0.0),
+                        (_, Some(x)) => StackType::Double(x.signum()),
+                        _ => {
+                            return Err(CalcError::TypeMismatch {
+                                expected: "number",
+                                found: Box::new(a),
+                            })
+                        }
+                    };
+                    self.stack.push(res);
                 }
-                Instruction::TanR => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.tan()));
+                Instruction::Fmod => {
+                    let (a, b) = self.get_samenum()?;
+                    if is_zero(&a) {
+                        return Err(CalcError::DivisionByZero);
+                    }
+                    // the sign of the dividend, as the truncated division
+                    match (&a, &b) {
+                        (StackType::Int(a), StackType::Int(b)) => {
+                            self.stack.push(StackType::Int(b.wrapping_rem(*a)))
+                        }
+                        (StackType::Rational(a), StackType::Rational(b)) => {
+                            self.stack.push(rational(b % a))
+                        }
+                        (StackType::Double(a), StackType::Double(b)) => {
+                            self.stack.push(StackType::Double(b % a))
+                        }
+                        _ => {
+                            return Err(CalcError::TypeMismatch {
+                                expected: "real number",
+                                found: Box::new(b),
+                            })
+                        }
+                    }
                 }
-                Instruction::CosD => {
-                    let Some(a) = self.get_double() else { break };
-                    let a = a / 180. * std::f64::consts::PI;
-                    self.stack.push(StackType::Double(a.cos()));
+                Instruction::Hypot => {
+                    let a = self.get_double()?;
+                    let b = self.get_double()?;
+                    self.stack.push(StackType::Double(b.hypot(a)));
                 }
-                Instruction::SinD => {
-                    let Some(a) = self.get_double() else { break };
-                    let a = a / 180. * std::f64::consts::PI;
-                    self.stack.push(StackType::Double(a.sin()));
+                Instruction::Atan2 => {
+                    let x = self.get_double()?;
+                    let y = self.get_double()?;
+                    self.stack.push(StackType::Double(y.atan2(x)));
                 }
-                Instruction::TanD => {
-                    let Some(a) = self.get_double() else { break };
-                    let a = a / 180. * std::f64::consts::PI;
-                    self.stack.push(StackType::Double(a.tan()));
+                Instruction::Min | Instruction::Max => {
+                    let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) else {
+                        return Err(CalcError::StackUnderflow);
+                    };
+                    // compared as the relations, the result keeps its type
+                    self.stack.push(b.clone());
+                    self.stack.push(a.clone());
+                    let greater = matches!(self.get_ordering()?, Some(Greater));
+                    let max = self.prog[self.pc] == Instruction::Max;
+                    self.stack.push(if greater == max { b } else { a });
+                }
+                Instruction::Floor
+                | Instruction::Ceil
+                | Instruction::Round
+                | Instruction::Trunc => {
+                    // an integer is already rounded
+                    if let Some(StackType::Rational(a)) = self.stack.last() {
+                        let res = match self.prog[self.pc] {
+                            Instruction::Floor => a.floor(),
+                            Instruction::Ceil => a.ceil(),
+                            Instruction::Round => a.round(),
+                            _ => a.trunc(),
+                        };
+                        self.stack.pop();
+                        self.stack.push(rational(res));
+                    } else if !matches!(self.stack.last(), Some(StackType::Int(_))) {
+                        let a = self.get_double()?;
+                        self.stack.push(StackType::Double(match self.prog[self.pc] {
+                            Instruction::Floor => a.floor(),
+                            Instruction::Ceil => a.ceil(),
+                            Instruction::Round => a.round(),
+                            _ => a.trunc(),
+                        }));
+                    }
                 }
+
+                // Trigonometric function
+                Instruction::CosR => self.unary_fn(f64::cos, Complex::cos, |_| true)?,
+                Instruction::SinR => self.unary_fn(f64::sin, Complex::sin, |_| true)?,
+                Instruction::TanR => self.unary_fn(f64::tan, Complex::tan, |_| true)?,
+                Instruction::CosD => self.unary_fn(|a| deg(a).cos(), |a| deg(a).cos(), |_| true)?,
+                Instruction::SinD => self.unary_fn(|a| deg(a).sin(), |a| deg(a).sin(), |_| true)?,
+                Instruction::TanD => self.unary_fn(|a| deg(a).tan(), |a| deg(a).tan(), |_| true)?,
                 Instruction::AcosR => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.acos()));
+                    self.unary_fn(f64::acos, Complex::acos, |a| a.abs() <= 1.0)?
                 }
                 Instruction::AsinR => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.asin()));
-                }
-                Instruction::AtanR => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.atan()));
-                }
-                Instruction::AcosD => {
-                    let Some(a) = self.get_double() else { break };
-                    let a = a.acos() * 180. / std::f64::consts::PI;
-                    self.stack.push(StackType::Double(a));
-                }
-                Instruction::AsinD => {
-                    let Some(a) = self.get_double() else { break };
-                    let a = a.asin() * 180. / std::f64::consts::PI;
-                    self.stack.push(StackType::Double(a));
+                    self.unary_fn(f64::asin, Complex::asin, |a| a.abs() <= 1.0)?
+                }
+                Instruction::AtanR => self.unary_fn(f64::atan, Complex::atan, |_| true)?,
+                Instruction::AcosD => self.unary_fn(
+                    |a| a.acos().to_degrees(),
+                    |a| a.acos().unscale(PI / 180.),
+                    |a| a.abs() <= 1.0,
+                )?,
+                Instruction::AsinD => self.unary_fn(
+                    |a| a.asin().to_degrees(),
+                    |a| a.asin().unscale(PI / 180.),
+                    |a| a.abs() <= 1.0,
+                )?,
+                Instruction::AtanD => self.unary_fn(
+                    |a| a.atan().to_degrees(),
+                    |a| a.atan().unscale(PI / 180.),
+                    |_| true,
+                )?,
+                Instruction::Sinh => self.unary_fn(f64::sinh, Complex::sinh, |_| true)?,
+                Instruction::Cosh => self.unary_fn(f64::cosh, Complex::cosh, |_| true)?,
+                Instruction::Tanh => self.unary_fn(f64::tanh, Complex::tanh, |_| true)?,
+                Instruction::Asinh => self.unary_fn(f64::asinh, Complex::asinh, |_| true)?,
+                Instruction::Acosh => self.unary_fn(f64::acosh, Complex::acosh, |a| a >= 1.0)?,
+                Instruction::Atanh => {
+                    self.unary_fn(f64::atanh, Complex::atanh, |a| a.abs() <= 1.0)?
                 }
-                Instruction::AtanD => {
-                    let Some(a) = self.get_double() else { break };
-                    let a = a.atan() * 180. / std::f64::consts::PI;
-                    self.stack.push(StackType::Double(a));
-                }
-                // Logarithm and exponential
-                Instruction::Loge => {
-                    let Some(a) = self.stack.pop() else {
-                        eprintln!("Stack is empty!");
-                        break;
-                    };
-                    match a {
-                        StackType::Double(aa) => self.stack.push(StackType::Double(aa.ln())),
-                        StackType::Complex(aa) => self.stack.push(StackType::Complex(aa.ln())),
-                        _ => eprintln!("Loge type error."),
+
+                // Special functions
+                Instruction::Gamma => {
+                    let a = self.get_double()?;
+                    self.stack.push(StackType::Double(special::gamma(a)));
+                }
+                Instruction::Lgamma => {
+                    let a = self.get_double()?;
+                    self.stack.push(StackType::Double(special::lgamma(a)));
+                }
+                Instruction::Erf => {
+                    let a = self.get_double()?;
+                    self.stack.push(StackType::Double(special::erf(a)));
+                }
+                Instruction::Erfc => {
+                    let a = self.get_double()?;
+                    self.stack.push(StackType::Double(special::erfc(a)));
+                }
+                Instruction::BesselJ0 => {
+                    let a = self.get_double()?;
+                    let j = special::bessel_j(0, a).ok_or(CalcError::OutOfRange)?;
+                    self.stack.push(StackType::Double(j));
+                }
+                Instruction::BesselJ1 => {
+                    let a = self.get_double()?;
+                    let j = special::bessel_j(1, a).ok_or(CalcError::OutOfRange)?;
+                    self.stack.push(StackType::Double(j));
+                }
+                Instruction::BesselJn => {
+                    let x = self.get_double()?;
+                    let n = self.get_int()?;
+                    let j = special::bessel_j(n, x).ok_or(CalcError::OutOfRange)?;
+                    self.stack.push(StackType::Double(j));
+                }
+                Instruction::BesselI0 => {
+                    let a = self.get_double()?;
+                    self.stack.push(StackType::Double(special::bessel_i0(a)));
+                }
+                Instruction::Factorial => {
+                    if let Some(&StackType::Int(n)) = self.stack.last() {
+                        self.stack.pop();
+                        let res = self.exact_count(false, n, n)?;
+                        self.stack.push(res);
+                    } else {
+                        let n = self.get_double()?;
+                        self.stack.push(StackType::Double(special::gamma(n + 1.0)));
                     }
                 }
-                Instruction::Log2 => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.log2()));
-                }
-                Instruction::Log10 => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.log10()));
-                }
-                Instruction::Logx => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(b.ln() / a.ln()));
+                Instruction::Ncr | Instruction::Npr => {
+                    let ncr = self.prog[self.pc] == Instruction::Ncr;
+                    if let [.., StackType::Int(n), StackType::Int(r)] = self.stack[..] {
+                        self.stack.truncate(self.stack.len() - 2);
+                        let res = self.exact_count(ncr, n, r)?;
+                        self.stack.push(res);
+                    } else {
+                        let r = self.get_double()?;
+                        let n = self.get_double()?;
+                        if !(n >= 0.0 && r >= 0.0 && n.fract() == 0.0 && r.fract() == 0.0) {
+                            return Err(CalcError::BadArgument);
+                        }
+                        self.stack.push(StackType::Double(if r > n {
+                            0.0
+                        } else if ncr {
+                            special::combinations(n, r)
+                        } else {
+                            special::permutations(n, r)
+                        }));
+                    }
                 }
 
-                Instruction::Expe => {
-                    let Some(a) = self.stack.pop() else {
-                        eprintln!("Stack is empty!");
-                        break;
+                // Number theory
+                Instruction::Gcd | Instruction::Lcm => {
+                    let a = self.get_signed()?.unsigned_abs();
+                    let b = self.get_signed()?.unsigned_abs();
+                    let gcd = numtheory::gcd(b, a);
+                    let res = match self.prog[self.pc] {
+                        Instruction::Gcd => gcd as u128,
+                        _ if gcd == 0 => 0,
+                        _ => (b / gcd) as u128 * a as u128,
                     };
-                    match a {
-                        StackType::Double(aa) => self.stack.push(StackType::Double(aa.exp())),
-                        StackType::Complex(aa) => self.stack.push(StackType::Complex(aa.exp())),
-                        _ => eprintln!("Exp type error."),
+                    let Ok(res) = i64::try_from(res) else {
+                        return Err(CalcError::IntegerOverflow);
+                    };
+                    self.stack.push(StackType::Int(res));
+                }
+                Instruction::Mod => {
+                    let (a, b) = self.get_samenum()?;
+                    if is_zero(&a) {
+                        return Err(CalcError::DivisionByZero);
+                    }
+                    match (&a, &b) {
+                        (StackType::Int(a), StackType::Int(b)) => self
+                            .stack
+                            .push(StackType::Int(numtheory::floor_mod(*b, *a))),
+                        (StackType::Rational(a), StackType::Rational(b)) => {
+                            self.stack.push(rational(b - a * (b / a).floor()))
+                        }
+                        (StackType::Double(a), StackType::Double(b)) => {
+                            let r = b % a;
+                            let r = if r != 0.0 && (r < 0.0) != (*a < 0.0) {
+                                r + a
+                            } else {
+                                r
+                            };
+                            self.stack.push(StackType::Double(r))
+                        }
+                        _ => {
+                            return Err(CalcError::TypeMismatch {
+                                expected: "real number",
+                                found: Box::new(b),
+                            })
+                        }
                     }
                 }
-                Instruction::Exp2 => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(a.exp2()));
+                Instruction::ModPow => {
+                    let m = self.get_signed()?;
+                    let e = self.get_signed()?;
+                    let b = self.get_signed()?;
+                    if m <= 0 || e < 0 {
+                        return Err(CalcError::BadArgument);
+                    }
+                    let b = numtheory::floor_mod(b, m) as u64;
+                    let res = numtheory::pow_mod(b, e as u64, m as u64);
+                    self.stack.push(StackType::Int(res as i64));
+                }
+                Instruction::ModInv => {
+                    let m = self.get_signed()?;
+                    let a = self.get_signed()?;
+                    if m <= 0 {
+                        return Err(CalcError::BadArgument);
+                    }
+                    self.stack.push(StackType::Int(numtheory::inv_mod(a, m)?));
+                }
+                Instruction::IsPrime => {
+                    let a = self.get_signed()?;
+                    let prime = a > 0 && numtheory::is_prime(a as u64);
+                    self.stack.push(StackType::Double(prime as i32 as f64));
+                }
+                Instruction::NextPrime => {
+                    let a = self.get_signed()?.max(0);
+                    let res = numtheory::next_prime(a as u64).and_then(|p| i64::try_from(p).ok());
+                    let Some(res) = res else {
+                        return Err(CalcError::IntegerOverflow);
+                    };
+                    self.stack.push(StackType::Int(res));
                 }
-                Instruction::Exp10 => {
-                    let Some(a) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(10_f64.powf(a)));
+                Instruction::Factor => {
+                    // a negative number has the factor -1
+                    let a = self.get_signed()?;
+                    if a < 0 {
+                        self.stack.push(StackType::Int(-1));
+                    }
+                    for p in numtheory::factor(a.unsigned_abs()) {
+                        self.stack.push(StackType::Int(p as i64));
+                    }
+                    if self.stack.len() >= MAX_STACK {
+                        return Err(CalcError::StackOverflow);
+                    }
                 }
-                Instruction::Expx => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack.push(StackType::Double(b.powf(a)));
+                Instruction::VFactor(regnum) => {
+                    let a = self.get_signed()?;
+                    let mut factors: Vec<f64> = numtheory::factor(a.unsigned_abs())
+                        .into_iter()
+                        .map(|p| p as f64)
+                        .collect();
+                    if a < 0 {
+                        factors.insert(0, -1.0);
+                    }
+                    self.stack.push(StackType::Int(factors.len() as i64));
+                    self.vectors[regnum as usize].data_type = Type::Double;
+                    self.vectors[regnum as usize].vector = factors;
                 }
+
+                // Logarithm and exponential
+                Instruction::Loge => self.unary_fn(f64::ln, Complex::ln, |a| a >= 0.0)?,
+                Instruction::Log2 => self.unary_fn(f64::log2, Complex::log2, |a| a >= 0.0)?,
+                Instruction::Log10 => self.unary_fn(f64::log10, Complex::log10, |a| a >= 0.0)?,
+                Instruction::Logx => self.binary_fn(
+                    |b, a| b.ln() / a.ln(),
+                    |b, a| b.ln() / a.ln(),
+                    |b, a| b >= 0.0 && a >= 0.0,
+                )?,
+
+                Instruction::Expe => self.unary_fn(f64::exp, Complex::exp, |_| true)?,
+                Instruction::Exp2 => self.unary_fn(f64::exp2, Complex::exp2, |_| true)?,
+                Instruction::Exp10 => self.unary_fn(
+                    |a| 10_f64.powf(a),
+                    |a| a.scale(std::f64::consts::LN_10).exp(),
+                    |_| true,
+                )?,
+                // negative base only with integer exponent
+                Instruction::Expx => self.binary_fn(f64::powf, Complex::powc, |b, a| {
+                    b >= 0.0 || a.fract() == 0.0
+                })?,
                 Instruction::Gt => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack.push(StackType::Double((b > a) as i32 as f64));
+                    let ord = self.get_ordering()?;
+                    self.stack
+                        .push(StackType::Double(matches!(ord, Some(Greater)) as i32 as f64));
                 }
                 Instruction::Lt => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack.push(StackType::Double((b < a) as i32 as f64));
+                    let ord = self.get_ordering()?;
+                    self.stack
+                        .push(StackType::Double(matches!(ord, Some(Less)) as i32 as f64));
                 }
                 Instruction::Ge => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack.push(StackType::Double((b >= a) as i32 as f64));
+                    let ord = self.get_ordering()?;
+                    self.stack.push(StackType::Double(
+                        matches!(ord, Some(Greater | Equal)) as i32 as f64,
+                    ));
                 }
                 Instruction::Le => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack.push(StackType::Double((b <= a) as i32 as f64));
+                    let ord = self.get_ordering()?;
+                    self.stack.push(StackType::Double(
+                        matches!(ord, Some(Less | Equal)) as i32 as f64
+                    ));
                 }
                 Instruction::Eq => {
-                    let Some(a) = self.get_double() else { break };
-                    let Some(b) = self.get_double() else { break };
-                    self.stack.push(StackType::Double((b == a) as i32 as f64));
+                    let ord = self.get_ordering()?;
+                    self.stack
+                        .push(StackType::Double(matches!(ord, Some(Equal)) as i32 as f64));
                 }
 
                 // Complex
                 Instruction::Real => {
                     let Some(a) = self.stack.pop() else {
-                        eprintln!("Stack is empty!");
-                        break;
+                        return Err(CalcError::StackUnderflow);
                     };
                     let StackType::Complex(a) = a else {
-                        eprintln!("This program compute trigonometric value only with Double, not Complex.");
-                        break;
+                        return Err(CalcError::TypeMismatch {
+                            expected: "complex number",
+                            found: Box::new(a),
+                        });
                     };
                     self.stack.push(StackType::Double(a.re));
                 }
                 Instruction::Imag => {
                     let Some(a) = self.stack.pop() else {
-                        eprintln!("Stack is empty!");
-                        break;
+                        return Err(CalcError::StackUnderflow);
                     };
                     let StackType::Complex(a) = a else {
-                        eprintln!("This program compute trigonometric value only with Double, not Complex.");
-                        break;
+                        return Err(CalcError::TypeMismatch {
+                            expected: "complex number",
+                            found: Box::new(a),
+                        });
                     };
                     self.stack.push(StackType::Double(a.im));
                 }
+                Instruction::CplxMode(on) => self.cplx_mode = on,
+                Instruction::P2c => {
+                    let phase = self.get_double()?;
+                    let mag = self.get_double()?;
+                    let phase = if self.degrees {
+                        phase.to_radians()
+                    } else {
+                        phase
+                    };
+                    self.stack
+                        .push(StackType::Complex(Complex::from_polar(mag, phase)));
+                }
+                Instruction::C2p | Instruction::Arg => {
+                    let (mag, phase) = self.get_complex()?.to_polar();
+                    let phase = if self.degrees {
+                        phase.to_degrees()
+                    } else {
+                        phase
+                    };
+                    if self.prog[self.pc] == Instruction::C2p {
+                        self.stack.push(StackType::Double(mag));
+                    }
+                    self.stack.push(StackType::Double(phase));
+                }
+                Instruction::Conj => {
+                    let Some(a) = self.stack.pop() else {
+                        return Err(CalcError::StackUnderflow);
+                    };
+                    match a {
+                        StackType::Complex(a) => self.stack.push(StackType::Complex(a.conj())),
+                        // a real number is its own conjugate
+                        a if real(&a).is_some() => self.stack.push(a),
+                        a => {
+                            return Err(CalcError::TypeMismatch {
+                                expected: "number",
+                                found: Box::new(a),
+                            })
+                        }
+                    }
+                }
                 Instruction::R2c => {
                     let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) else {
-                        eprintln!("Stack is empty!");
-                        break;
+                        return Err(CalcError::StackUnderflow);
                     };
-                    let (StackType::Double(a), StackType::Double(b)) = (a, b) else {
-                        eprintln!("Numbers are not real!");
-                        break;
+                    let (StackType::Double(a), StackType::Double(b)) = (&a, &b) else {
+                        return Err(CalcError::TypeMismatch {
+                            expected: "two real numbers",
+                            found: Box::new(if let StackType::Double(_) = a { b } else { a }),
+                        });
                     };
-                    self.stack.push(StackType::Complex(Complex::new(b, a)));
+                    self.stack.push(StackType::Complex(Complex::new(*b, *a)));
                 }
 
                 // Registers
                 Instruction::Save(regnum) => {
                     let Some(x) = self.stack.pop() else {
-                        eprintln!("Stack is empty!");
-                        break;
+                        return Err(CalcError::StackUnderflow);
                     };
                     self.registers[regnum as usize] = x;
                 }
                 Instruction::Load(regnum) => {
-                    self.stack.push(self.registers[regnum as usize]);
+                    self.stack.push(self.registers[regnum as usize].clone());
                     if self.stack.len() >= MAX_STACK {
-                        eprintln!(
-                            "Stack is FULL ({} element)! Please clear it.",
-                            self.stack.len()
-                        );
-                        break;
+                        return Err(CalcError::StackOverflow);
                     }
                 }
                 Instruction::Creg(regnum) => {
@@ -509,70 +1754,86 @@ impl Runner {
                             ok = true;
                         }
                     }
+                    for n in &self.names {
+                        let kind = if n.constant { "Const" } else { "Var" };
+                        println!("{kind:5} {}: {:?}", n.name, n.value);
+                        ok = true;
+                    }
                     if !ok {
                         println!("Not found any defined registers. Use RNUM save for save.")
                     }
                 }
+                Instruction::Store(slot) => {
+                    let Some(x) = self.stack.pop() else {
+                        return Err(CalcError::StackUnderflow);
+                    };
+                    self.names[slot].value = x;
+                }
+                Instruction::Fetch(slot) => {
+                    self.stack.push(self.names[slot].value.clone());
+                    if self.stack.len() >= MAX_STACK {
+                        return Err(CalcError::StackOverflow);
+                    }
+                }
 
                 // Vectors
                 Instruction::Vreal(regnum) => {
                     // vector create complex - with LEN
-                    let Some(a) = self.get_double() else { break };
+                    let a = self.get_length()?;
                     self.vectors[regnum as usize].data_type = Type::Double;
-                    self.vectors[regnum as usize].vector = vec![0.0; a as usize];
+                    self.vectors[regnum as usize].vector = vec![0.0; a];
                 }
                 Instruction::Vcplx(regnum) => {
                     // vector create complex - with LEN
-                    let Some(a) = self.get_double() else { break };
+                    let a = self.get_length()?;
                     self.vectors[regnum as usize].data_type = Type::Complex;
-                    self.vectors[regnum as usize].vector = vec![0.0; 2 * a as usize];
+                    self.vectors[regnum as usize].vector = vec![0.0; 2 * a];
                 }
                 Instruction::Vsave(regnum) => {
                     // vsaveX
-                    let Some(a) = self.get_double() else { break };
+                    let a = self.get_index(regnum)?;
                     let Some(b) = self.stack.pop() else {
-                        eprintln!("Stack empty");
-                        break;
-                    };
-                    match b {
-                        StackType::Double(bb) => {
-                            if self.vectors[regnum as usize].data_type != Type::Double {
-                                eprintln!("Type error: vector is a real vector.");
-                                break;
-                            }
-                            self.vectors[regnum as usize].vector[a as usize] = bb
+                        return Err(CalcError::StackUnderflow);
+                    };
+                    if let Some(bb) = real(&b) {
+                        if self.vectors[regnum as usize].data_type != Type::Double {
+                            return Err(CalcError::TypeMismatch {
+                                expected: "complex number for a complex vector",
+                                found: Box::new(b),
+                            });
                         }
-                        StackType::Complex(bb) => {
-                            if self.vectors[regnum as usize].data_type != Type::Complex {
-                                eprintln!("Type error: vector is a complex vector.");
-                                break;
-                            }
-                            self.vectors[regnum as usize].vector[2 * a as usize] = bb.re;
-                            self.vectors[regnum as usize].vector[2 * a as usize + 1] = bb.im;
+                        self.vectors[regnum as usize].vector[a] = bb;
+                    } else if let StackType::Complex(bb) = b {
+                        if self.vectors[regnum as usize].data_type != Type::Complex {
+                            return Err(CalcError::TypeMismatch {
+                                expected: "real number for a real vector",
+                                found: Box::new(b),
+                            });
                         }
-                        StackType::None => (),
+                        self.vectors[regnum as usize].vector[2 * a] = bb.re;
+                        self.vectors[regnum as usize].vector[2 * a + 1] = bb.im;
+                    } else if b != StackType::None {
+                        return Err(CalcError::TypeMismatch {
+                            expected: "number",
+                            found: Box::new(b),
+                        });
                     }
                 }
                 Instruction::Vload(regnum) => {
                     // vloadX
-                    let Some(a) = self.get_double() else { break };
+                    let a = self.get_index(regnum)?;
                     if self.vectors[regnum as usize].data_type == Type::Double {
-                        self.stack.push(StackType::Double(
-                            self.vectors[regnum as usize].vector[a as usize],
-                        ));
+                        self.stack
+                            .push(StackType::Double(self.vectors[regnum as usize].vector[a]));
                     } else {
                         // Complex
                         self.stack.push(StackType::Complex(Complex::new(
-                            self.vectors[regnum as usize].vector[2 * a as usize],
-                            self.vectors[regnum as usize].vector[2 * a as usize + 1],
+                            self.vectors[regnum as usize].vector[2 * a],
+                            self.vectors[regnum as usize].vector[2 * a + 1],
                         )));
                     }
                     if self.stack.len() >= MAX_STACK {
-                        eprintln!(
-                            "Stack is FULL ({} element)! Please clear it.",
-                            self.stack.len()
-                        );
-                        break;
+                        return Err(CalcError::StackOverflow);
                     }
                 }
                 Instruction::Cvec(regnum) => {
@@ -603,48 +1864,82 @@ impl Runner {
 
                 // Print and related
                 Instruction::FractionalDigit => {
-                    let Some(StackType::Double(a)) = self.stack.pop() else {
-                        eprintln!("FractionalDigit");
-                        break;
-                    };
+                    let a = self.get_double()?;
                     if a <= 17.0 {
                         self.fractionaldigit = a as usize;
                     }
                 }
                 Instruction::Print => {
                     let Some(a) = self.stack.last() else {
-                        eprintln!("Stack is empty.");
-                        break;
+                        return Err(CalcError::StackUnderflow);
                     };
-                    match a {
-                        StackType::Double(res) => {
-                            if self.fractionaldigit > 0 {
-                                println!("Result: {res:.*?}", self.fractionaldigit);
-                            } else {
-                                println!("Result: {res:?}");
-                            }
-                        }
-                        StackType::Complex(res) => {
-                            if self.fractionaldigit > 0 {
-                                println!("Result: {res:.*?}", self.fractionaldigit);
-                            } else {
-                                println!("Result: {res:?}");
-                            }
-                        }
-                        _ => (),
+                    if *a != StackType::None {
+                        println!("Result: {}", self.format(a));
+                    }
+                }
+                #[cfg(feature = "bigdecimal")]
+                Instruction::Precision(digits) => self.precision = digits,
+                Instruction::Radix(radix) => self.radix = radix,
+                Instruction::WordSize => {
+                    let a = self.get_double()?;
+                    if ![8.0, 16.0, 32.0, 64.0].contains(&a) {
+                        return Err(CalcError::BadWordSize(a));
+                    }
+                    self.word_bits = a as u32;
+                }
+                Instruction::Signed(signed) => self.signed = signed,
+                Instruction::Polar(polar) => self.polar = polar,
+                Instruction::Degrees(degrees) => self.degrees = degrees,
+
+                // Strings
+                Instruction::Type => {
+                    let s = self.get_string()?;
+                    print!("{s}");
+                    let _ = std::io::stdout().flush();
+                }
+                Instruction::Cr => println!(),
+                Instruction::Len => {
+                    let s = self.get_string()?;
+                    self.stack.push(StackType::Double(s.chars().count() as f64));
+                }
+                Instruction::ToStr => {
+                    let Some(a) = self.stack.pop() else {
+                        return Err(CalcError::StackUnderflow);
                     };
+                    if a == StackType::None {
+                        return Err(CalcError::TypeMismatch {
+                            expected: "number or string",
+                            found: Box::new(a),
+                        });
+                    }
+                    self.stack.push(StackType::Str(self.format(&a)));
+                }
+                Instruction::StrTo => {
+                    let s = self.get_string()?;
+                    let t = s.trim();
+                    if let Some(num) = parse_radix(t) {
+                        self.stack.push(StackType::Int(num?));
+                    } else {
+                        let num = match t.strip_suffix('j') {
+                            Some(im) => im
+                                .parse()
+                                .map(|im| StackType::Complex(Complex::new(0.0, im))),
+                            None => t.parse().map(StackType::Double),
+                        };
+                        let Ok(num) = num else {
+                            return Err(CalcError::BadNumber(s));
+                        };
+                        self.stack.push(num);
+                    }
                 }
 
                 Instruction::Quit => {
-                    eprintln!("Exit from calculator. Bye.");
-                    std::process::exit(0);
+                    self.quit = true;
+                    return Ok(());
                 }
             } // match
             self.pc += 1;
         } // while
-          // if breaked, drop the remaining part of the program
-        if self.pc < self.prog.len() {
-            self.pc = self.prog.len();
-        }
-    } // fn run
+        Ok(())
+    } // fn exec
 } // Obj
@@ -0,0 +1,259 @@
+// Special functions of real numbers, in f64.
+// Accuracy, checked against mpmath:
+//   gamma:      Lanczos approximation (g = 7, 9 terms), 3e-15 relative below 10,
+//               1e-13 near 171 (the power of the large numbers); exact at the integers
+//   lgamma:     the same, 2e-15 relative, or absolute near its zeros (1 and 2)
+//   erf, erfc:  series below 2 (erfc: 0.5), continued fraction above, 3e-15 relative
+//   J0, J1, Jn: Miller's backward recurrence, Hankel's asymptotic series above 25,
+//               2e-15 absolute, 1e-14 at 1e5 (the zeros of J have no relative accuracy),
+//               orders up to 100000 or where Jn underflows
+//   I0:         power series, 2e-15 relative
+// n!, nCr and nPr of integers are exact, see the runner.
+use num_bigint::BigInt;
+use num_traits::One;
+use std::f64::consts::{E, PI};
+
+const LANCZOS_G: f64 = 7.0;
+const LANCZOS: [f64; 9] = [
+    0.999_999_999_999_809_9,
+    676.520_368_121_885_1,
+    -1_259.139_216_722_402_8,
+    771.323_428_777_653_1,
+    -176.615_029_162_140_6,
+    12.507_343_278_686_905,
+    -0.138_571_095_265_720_12,
+    9.984_369_578_019_572e-6,
+    1.505_632_735_149_311_6e-7,
+];
+const SQRT_2PI: f64 = 2.506_628_274_631_000_7;
+const MAX_FACTORIAL: f64 = 170.0; // 171! is infinite in f64
+const HANKEL_LIMIT: f64 = 25.0; // the asymptotic series of J above it
+const MAX_BESSEL_ORDER: u64 = 100_000; // the recurrences of Jn run about n steps
+
+// sin(pi x), exact at the integers
+fn sin_pi(x: f64) -> f64 {
+    let r = x - 2.0 * (x / 2.0).floor();
+    if r.fract() == 0.0 {
+        0.0
+    } else {
+        (PI * r).sin()
+    }
+}
+
+// The Lanczos sum and t = x + g - 0.5, gamma(x) = sqrt(2 pi) t^(x - 0.5) e^-t sum, x >= 0.5
+fn lanczos(x: f64) -> (f64, f64) {
+    let x = x - 1.0;
+    let sum = LANCZOS[1..]
+        .iter()
+        .enumerate()
+        .fold(LANCZOS[0], |sum, (i, c)| sum + c / (x + i as f64 + 1.0));
+    (sum, x + LANCZOS_G + 0.5)
+}
+
+// Gamma function, NaN at the poles (0, -1, -2 ...), exact product at the small integers
+pub fn gamma(x: f64) -> f64 {
+    if x <= 0.0 && x.fract() == 0.0 || x.is_nan() {
+        f64::NAN
+    } else if x.fract() == 0.0 && x <= MAX_FACTORIAL + 1.0 {
+        (2..x as u32).fold(1.0, |p, k| p * k as f64)
+    } else if x < 0.5 {
+        // reflection
+        PI / (sin_pi(x) * gamma(1.0 - x))
+    } else {
+        let (sum, t) = lanczos(x);
+        // t^(x - 0.5) in two halves, it overflows before e^-t
+        let half = t.powf((x - 0.5) / 2.0);
+        SQRT_2PI * half * (half * (-t).exp()) * sum
+    }
+}
+
+// ln |gamma(x)|, infinite at the poles
+pub fn lgamma(x: f64) -> f64 {
+    if x <= 0.0 && x.fract() == 0.0 {
+        f64::INFINITY
+    } else if x < 0.5 {
+        (PI / sin_pi(x).abs()).ln() - lgamma(1.0 - x)
+    } else {
+        let (sum, t) = lanczos(x);
+        SQRT_2PI.ln() + (x - 0.5) * t.ln() - t + sum.ln()
+    }
+}
+
+// erf(x) = 2/sqrt(pi) e^-x^2 (x + 2x^3/3 + 4x^5/15 + ...), the terms are positive
+fn erf_series(x: f64) -> f64 {
+    let x2 = x * x;
+    let mut term = x;
+    let mut sum = x;
+    let mut n = 0.0;
+    while term.abs() > sum.abs() * 1e-17 {
+        n += 1.0;
+        term *= 2.0 * x2 / (2.0 * n + 1.0);
+        sum += term;
+    }
+    2.0 / PI.sqrt() * (-x2).exp() * sum
+}
+
+// erfc(x) = e^-x^2/sqrt(pi) 1/(x + 1/2/(x + 1/(x + 3/2/(x + ...)))), x > 0, Lentz's method
+fn erfc_fraction(x: f64) -> f64 {
+    let tiny = 1e-300;
+    let mut f = x;
+    let mut c = x;
+    let mut d = 0.0;
+    for n in 1..10_000 {
+        let a = n as f64 / 2.0;
+        d = x + a * d;
+        d = if d == 0.0 { 1.0 / tiny } else { 1.0 / d };
+        c = x + a / c;
+        if c == 0.0 {
+            c = tiny;
+        }
+        let delta = c * d;
+        f *= delta;
+        if (delta - 1.0).abs() < 1e-16 {
+            break;
+        }
+    }
+    (-x * x).exp() / PI.sqrt() / f
+}
+
+pub fn erf(x: f64) -> f64 {
+    if x.abs() < 2.0 {
+        erf_series(x)
+    } else {
+        (1.0 - erfc_fraction(x.abs())).copysign(x)
+    }
+}
+
+pub fn erfc(x: f64) -> f64 {
+    if x >= 0.5 {
+        erfc_fraction(x)
+    } else if x <= -2.0 {
+        2.0 - erfc_fraction(-x)
+    } else {
+        1.0 - erf_series(x)
+    }
+}
+
+// J0 and J1 of x > HANKEL_LIMIT: sqrt(2 / (pi x)) (P cos(chi) - Q sin(chi))
+fn bessel_hankel(nu: f64, x: f64) -> f64 {
+    let mu = 4.0 * nu * nu;
+    let (mut p, mut q) = (1.0, 0.0);
+    let mut term = 1.0f64;
+    for k in 1..100 {
+        let odd = (2 * k - 1) as f64;
+        let next = term * (mu - odd * odd) / (k as f64 * 8.0 * x);
+        // asymptotic, stop at the smallest term
+        if next.abs() >= term.abs() || next == 0.0 {
+            break;
+        }
+        term = next;
+        match k % 4 {
+            1 => q += term,
+            2 => p -= term,
+            3 => q -= term,
+            _ => p += term,
+        }
+    }
+    let chi = x - (nu / 2.0 + 0.25) * PI;
+    (2.0 / (PI * x)).sqrt() * (p * chi.cos() - q * chi.sin())
+}
+
+// Bessel function of the first kind, integer order. None above MAX_BESSEL_ORDER,
+// unless it underflows.
+pub fn bessel_j(n: i64, x: f64) -> Option<f64> {
+    // J-n(x) = Jn(-x) = (-1)^n Jn(x)
+    let sign = if n % 2 != 0 && (n < 0) != (x < 0.0) {
+        -1.0
+    } else {
+        1.0
+    };
+    let (n, x) = (n.unsigned_abs(), x.abs());
+    if x.is_nan() {
+        return Some(f64::NAN);
+    }
+    if x == 0.0 {
+        return Some(if n == 0 { 1.0 } else { 0.0 });
+    }
+    if n > MAX_BESSEL_ORDER {
+        // |Jn(x)| <= (x/2)^n / n! <= (e x / 2n)^n, below the smallest f64 it is 0
+        let n = n as f64;
+        return (n * (E * x / (2.0 * n)).ln() < -746.0).then_some(0.0);
+    }
+    if x > HANKEL_LIMIT && n as f64 <= x {
+        // the forward recurrence is stable below the order x
+        let (mut jm, mut j) = (bessel_hankel(0.0, x), bessel_hankel(1.0, x));
+        if n == 0 {
+            return Some(sign * jm);
+        }
+        for k in 1..n {
+            (jm, j) = (j, 2.0 * k as f64 / x * j - jm);
+        }
+        return Some(sign * j);
+    }
+    // Miller: from an order far above n and x, normalized by J0 + 2 J2 + 2 J4 ... = 1
+    let top = n.max(x as u64) as f64;
+    let m = 2 * ((top + 20.0 + 6.0 * top.cbrt()) as u64 / 2 + 1);
+    let (mut jp, mut j) = (0.0, 1.0f64); // J(k+1), J(k), unnormalized
+    let (mut res, mut sum) = (0.0, 0.0);
+    for k in (1..=m).rev() {
+        (jp, j) = (j, 2.0 * k as f64 / x * j - jp);
+        if j.abs() > 1e250 {
+            // rescale
+            j *= 1e-250;
+            jp *= 1e-250;
+            res *= 1e-250;
+            sum *= 1e-250;
+        }
+        // j is J(k - 1)
+        if k - 1 == n {
+            res = j;
+        }
+        if (k - 1) % 2 == 0 {
+            sum += if k == 1 { j } else { 2.0 * j };
+        }
+    }
+    Some(sign * res / sum)
+}
+
+// Modified Bessel function of the first kind, order 0: sum (x^2/4)^k / k!^2
+pub fn bessel_i0(x: f64) -> f64 {
+    let y = x * x / 4.0;
+    let mut term = 1.0;
+    let mut sum = 1.0f64;
+    let mut k = 0.0;
+    while term > sum * 1e-17 && sum.is_finite() {
+        k += 1.0;
+        term *= y / (k * k);
+        sum += term;
+    }
+    sum
+}
+
+// lo * (lo + 1) * ... * hi, exact
+pub fn product(lo: u64, hi: u64) -> BigInt {
+    (lo..=hi).fold(BigInt::one(), |p, k| p * k)
+}
+
+// n (n - 1) ... (n - r + 1) of integers, in f64
+pub fn permutations(n: f64, r: f64) -> f64 {
+    let mut res = 1.0f64;
+    let mut k = 0.0;
+    while k < r && res.is_finite() {
+        res *= n - k;
+        k += 1.0;
+    }
+    res
+}
+
+// n! / (r! (n - r)!) of integers, in f64. Exact below 2^53, the steps
+// C(n, k + 1) = C(n, k) (n - k) / (k + 1) are integers.
+pub fn combinations(n: f64, r: f64) -> f64 {
+    let r = r.min(n - r);
+    let mut res = 1.0f64;
+    let mut k = 0.0;
+    while k < r && res.is_finite() {
+        res = res * (n - k) / (k + 1.0);
+        k += 1.0;
+    }
+    res
+}
@@ -0,0 +1,831 @@
+use num_bigint::BigInt;
+use num_complex::Complex;
+use num_rational::BigRational;
+use proptest::prelude::*;
+use rpncalc::{CalcError, Calculator, Parser, StackType};
+use std::sync::atomic::Ordering;
+
+#[rustfmt::skip]
+const WORDS: &[&str] = &[
+    "dup", "drop", "over", "rot", "swap", "clear", "ds", "+", "-", "*", "/", "and", "or", "xor",
+    "neg", "shl", "shr", "abs", "floor", "ceil", "round", "cosr", "sinr", "tanr", "cosd", "sind",
+    "tand", "acosr", "asinr", "atanr", "acosd", "asind", "atand", "loge", "log2", "log10", "logx",
+    "expe", "exp2", "exp10", "expx", ">", "<", ">=", "<=", "=", "real", "imag", "r2c", "frdigit",
+    "p", "save", "load", "creg", "clregs", "dr", "vreal", "vcplx", "vsave", "vload", "cvec",
+    "clvecs", "dv", ":", ";", "dsr", "[", "]", "if", "else", "then", "do", "loop", "+loop", "i",
+    "j", "q", "sr", "sr", "0", "1", "2", "-1", "3", "255", "256", "-7", "0.5", "1e300", "1e20",
+    "3j", "-2.5j", "4j", "1-", "--", "x", "-j", "recurse", "defer", "is", "'", "forget", "marker",
+    "\"a b\"", "\"#x\"", ".\"hi\"", "\"", "type", "cr", "len", ">str", "str>", "{", "}", "{:", ":}",
+    "call", "times", "ifte", "catch", "throw", "include", "require", "vocab", "end-vocab", "using",
+    "v::x", "intmode", "floatmode", "rol", "ror", "popcount", "clz", ">int", ">float",
+    "9223372036854775807", "0x1F", "-0b1_0", "0xFFFF_FFFF_FFFF_FFFF", "0x", "hex", "dec", "bin",
+    "wordsize", "8", "unsigned", "signed", "sqrt", "precision", "exactmode", ">frac", "0.1", "1e-3",
+    "cplxmode", "realmode", "1@90d", "2@-1.5r", "@", "p2c", "c2p", "arg", "conj", "polar", "rect",
+    "degrees", "radians", "pow", "cbrt", "trunc", "sign", "fmod", "hypot", "atan2", "min", "max",
+    "sinh", "acosh", "atanh", "gamma", "lgamma", "erf", "erfc", "j0", "j1", "jn", "i0", "n!", "ncr",
+    "npr", "gcd", "lcm", "mod", "%", "modpow", "modinv", "isprime", "nextprime", "factor",
+    "vfactor", "1.5e-9223372036854775808",
+];
+
+fn word() -> impl Strategy<Value = &'static str> {
+    proptest::sample::select(WORDS)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(2000))]
+
+    #[test]
+    fn random_token_streams_do_not_panic(
+        lines in prop::collection::vec(prop::collection::vec(word(), 0..24), 1..6)
+    ) {
+        let mut p = Parser::new(false);
+        let stopped = p.stop_handle();
+        for line in lines {
+            // the first backward jump stops, so the random loops can't hang the test
+            stopped.store(true, Ordering::SeqCst);
+            let _ = p.parse_line(&line.join(" "));
+        }
+        prop_assert!(p.stack().len() < 1_000_000);
+    }
+}
+
+#[test]
+fn short_stack_and_bad_index_are_errors() {
+    let mut calc = Calculator::new();
+    assert_eq!(
+        calc.eval("1 over").unwrap_err().kind,
+        CalcError::StackUnderflow
+    );
+    let err = calc.eval("clear 8 3 vreal 1 9 3 vsave").unwrap_err();
+    assert_eq!(
+        err.kind,
+        CalcError::IndexOutOfRange {
+            vector: 3,
+            index: 9.0,
+            len: 8
+        }
+    );
+    let err = calc.eval("-1 3 vload").unwrap_err();
+    assert!(matches!(err.kind, CalcError::IndexOutOfRange { .. }));
+    let err = calc.eval("1e20 4 vcplx").unwrap_err();
+    assert_eq!(err.kind, CalcError::InvalidLength(1e20));
+    assert_eq!(
+        calc.eval("300 save").unwrap_err().kind,
+        CalcError::BadRegister("save".into())
+    );
+    // the shift count is not an index, all bits are shifted out
+    assert_eq!(
+        calc.eval("clear 1 64 shl").unwrap(),
+        vec![StackType::Int(0)]
+    );
+}
+
+#[test]
+fn errors_have_the_position_and_the_call_trace() {
+    let mut calc = Calculator::new();
+    calc.parser().set_source("script.rpn");
+    let err = calc
+        .eval(": inner 1 0 / ;\n: outer 2 inner ;\n  outer")
+        .unwrap_err();
+    let pos = err.pos.as_ref().unwrap();
+    assert_eq!((pos.line, pos.col, pos.token.as_str()), (1, 13, "/"));
+    // the innermost call first
+    let trace: Vec<_> = err
+        .trace
+        .iter()
+        .map(|p| (p.line, p.col, p.token.as_str()))
+        .collect();
+    assert_eq!(trace, vec![(2, 11, "inner"), (3, 3, "outer")]);
+    assert_eq!(
+        err.to_string(),
+        "script.rpn:1:13 '/': division by zero\n    called from script.rpn:2:11 'inner'\n    called from script.rpn:3:3 'outer'"
+    );
+    // parser errors have the position of the token
+    let err = calc.eval("clear 1 nosuchword").unwrap_err();
+    let pos = err.pos.unwrap();
+    assert_eq!(
+        (pos.line, pos.col, pos.token.as_str()),
+        (4, 9, "nosuchword")
+    );
+    assert!(err.trace.is_empty());
+}
+
+#[test]
+fn unbalanced_words_are_errors() {
+    let mut calc = Calculator::new();
+    assert_eq!(
+        calc.eval("1 ]").unwrap_err().kind,
+        CalcError::Unbalanced("]".into())
+    );
+    assert_eq!(
+        calc.eval(";").unwrap_err().kind,
+        CalcError::Unbalanced(";".into())
+    );
+    assert_eq!(
+        calc.eval(": a : b").unwrap_err().kind,
+        CalcError::Unbalanced(":".into())
+    );
+    assert_eq!(
+        calc.eval("1 if [ then ]").unwrap_err().kind,
+        CalcError::Unbalanced("then".into())
+    );
+    // the parser is usable after the errors
+    assert_eq!(
+        calc.eval("clear 1 2 +").unwrap(),
+        vec![StackType::Double(3.0)]
+    );
+}
+
+#[test]
+fn conditional_execution() {
+    let mut calc = Calculator::new();
+    let src = ": sgn dup 0 < if drop -1 else 0 > if 1 else 0 then then ;\n-5 sgn 0 sgn 3 sgn";
+    assert_eq!(
+        calc.eval(src).unwrap(),
+        vec![
+            StackType::Double(-1.0),
+            StackType::Double(0.0),
+            StackType::Double(1.0),
+        ]
+    );
+    assert_eq!(
+        calc.eval("clear 1 if 10 then 0 if 20 then").unwrap(),
+        vec![StackType::Double(10.0)]
+    );
+    // the pending number before "dsr" is not moved into the jump
+    assert_eq!(
+        calc.eval("clear 1 if 0 dsr\nelse 5 then").unwrap(),
+        vec![StackType::Double(0.0)]
+    );
+    assert_eq!(
+        calc.eval("clear 1 else").unwrap_err().kind,
+        CalcError::Unbalanced("else".into())
+    );
+    // still open at the end of the input
+    for (src, word) in [
+        ("1 if 2", "if"),
+        ("1 if 2 else 3", "else"),
+        (": f 1 2", ":"),
+        ("{ 1 2", "{"),
+        ("variable", "variable"),
+    ] {
+        assert_eq!(
+            calc.eval(src).unwrap_err().kind,
+            CalcError::Unbalanced(word.into())
+        );
+    }
+    assert_eq!(calc.eval("clear 1 2").unwrap().len(), 2);
+}
+
+#[test]
+fn counted_loops() {
+    let mut calc = Calculator::new();
+    assert_eq!(
+        calc.eval("0 11 1 do i + loop").unwrap(),
+        vec![StackType::Double(55.0)]
+    );
+    assert_eq!(
+        calc.eval("clear 0 10 do i -3 +loop").unwrap(),
+        vec![
+            StackType::Double(10.0),
+            StackType::Double(7.0),
+            StackType::Double(4.0),
+            StackType::Double(1.0),
+        ]
+    );
+    assert_eq!(
+        calc.eval("clear 3 1 do 3 1 do j 10 * i + loop loop")
+            .unwrap(),
+        vec![
+            StackType::Double(11.0),
+            StackType::Double(12.0),
+            StackType::Double(21.0),
+            StackType::Double(22.0),
+        ]
+    );
+    // an empty range runs the body once, as in FORTH
+    assert_eq!(
+        calc.eval("clear 5 5 do i loop").unwrap(),
+        vec![StackType::Double(5.0)]
+    );
+    assert_eq!(calc.eval("clear i").unwrap_err().kind, CalcError::NoLoop);
+}
+
+#[test]
+fn begin_loops() {
+    let mut calc = Calculator::new();
+    assert_eq!(
+        calc.eval("1 begin 2 * dup 100 > until").unwrap(),
+        vec![StackType::Double(128.0)]
+    );
+    // the body of while ... repeat may not run at all
+    assert_eq!(
+        calc.eval("clear 1 begin dup 100 < while 2 * repeat 200 begin dup 100 < while 2 * repeat")
+            .unwrap(),
+        vec![StackType::Double(128.0), StackType::Double(200.0)]
+    );
+    for (src, word) in [
+        ("1 until", "until"),
+        ("begin 1 repeat", "repeat"),
+        ("1 while", "while"),
+        ("begin 1 while 2 until", "until"),
+    ] {
+        assert_eq!(
+            calc.eval(src).unwrap_err().kind,
+            CalcError::Unbalanced(word.into())
+        );
+    }
+}
+
+#[test]
+fn variables_and_constants() {
+    let mut calc = Calculator::new();
+    let src = "variable rate 0.5 rate ! 42 constant answer\nrate @ answer 3 rate ! rate @";
+    assert_eq!(
+        calc.eval(src).unwrap(),
+        vec![
+            StackType::Double(0.5),
+            StackType::Double(42.0),
+            StackType::Double(3.0),
+        ]
+    );
+    assert_eq!(
+        calc.eval("clear rate 1").unwrap_err().kind,
+        CalcError::BadVariable("1".into())
+    );
+    assert_eq!(
+        calc.eval("clear @").unwrap_err().kind,
+        CalcError::BadVariable("@".into())
+    );
+    // the numbers are not shadowed
+    for src in [
+        ": 5 7 ;",
+        "variable 1",
+        "5 constant -2.5",
+        "variable 3j",
+        "1 constant 0x10",
+    ] {
+        assert!(matches!(
+            calc.eval(src).unwrap_err().kind,
+            CalcError::BadName(_)
+        ));
+    }
+    assert_eq!(
+        calc.eval("clear 1 0x10 +").unwrap(),
+        vec![StackType::Double(17.0)]
+    );
+}
+
+#[test]
+fn local_variables() {
+    let mut calc = Calculator::new();
+    let src = ": diff {: a b :} a b - ;\n: twice {: x :} x x diff x 2 * ;\n7 3 diff 5 twice";
+    assert_eq!(
+        calc.eval(src).unwrap(),
+        vec![
+            StackType::Double(4.0),
+            StackType::Double(0.0),
+            StackType::Double(10.0),
+        ]
+    );
+    // every call has its own frame
+    let src = ": fact {: n :} n 1 > if n 1 - fact n * else 1 then ;\nclear 5 fact";
+    assert_eq!(calc.eval(src).unwrap(), vec![StackType::Double(120.0)]);
+    let src = ": f {: :} ;\n: g {: a :} f a ;\nclear 5 g";
+    assert_eq!(calc.eval(src).unwrap(), vec![StackType::Double(5.0)]);
+    assert_eq!(
+        calc.eval("clear 1 diff").unwrap_err().kind,
+        CalcError::StackUnderflow
+    );
+    // a quotation doesn't see the locals of the word that made it
+    assert_eq!(
+        calc.eval(": h {: a b :} { b } ;").unwrap_err().kind,
+        CalcError::UnknownWord("b".into())
+    );
+}
+
+#[test]
+fn strings() {
+    let mut calc = Calculator::new();
+    assert_eq!(
+        calc.eval("\"a  b\" dup len \"x y\" \"z\" + \"3.5\" str>")
+            .unwrap(),
+        vec![
+            StackType::Str("a  b".into()),
+            StackType::Double(4.0),
+            StackType::Str("x yz".into()),
+            StackType::Double(3.5),
+        ]
+    );
+    assert_eq!(
+        calc.eval("clear \"a b").unwrap_err().kind,
+        CalcError::BadString("\"a b".into())
+    );
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rpncalc"))
+        .arg("-q")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdin = child.stdin.as_mut().unwrap();
+    std::io::Write::write_all(stdin, b"\"U = \" 2 >str + type cr .\"a  b\" cr\n").unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "U = 2.0\na  b\n");
+}
+
+#[test]
+fn quotations_and_combinators() {
+    let mut calc = Calculator::new();
+    // a quotation may open a subroutine body
+    let src = ": double { 2 * } call ;\n5 double 0 3 { 1 + } times";
+    assert_eq!(
+        calc.eval(src).unwrap(),
+        vec![StackType::Double(10.0), StackType::Double(3.0)]
+    );
+    assert_eq!(
+        calc.eval("clear 1 { 2 } { 3 } ifte 0 { 2 } { 3 } ifte")
+            .unwrap(),
+        vec![StackType::Double(2.0), StackType::Double(3.0)]
+    );
+    assert_eq!(
+        calc.eval("clear 1 0 { 1 + } times").unwrap(),
+        vec![StackType::Double(1.0)]
+    );
+    assert!(matches!(
+        calc.eval("clear 5 call").unwrap_err().kind,
+        CalcError::TypeMismatch { .. }
+    ));
+    assert_eq!(
+        calc.eval(": bad {: 2 :} ;").unwrap_err().kind,
+        CalcError::BadName("2".into())
+    );
+}
+
+#[test]
+fn endless_recursion_is_an_error() {
+    let mut calc = Calculator::new();
+    let err = calc.eval(": rec 1 rec ;\nrec").unwrap_err();
+    assert_eq!(err.kind, CalcError::ReturnStackOverflow);
+    calc.parser().set_max_depth(10);
+    let err = calc.eval("clear : down 1 recurse ;\ndown").unwrap_err();
+    assert_eq!(err.kind, CalcError::ReturnStackOverflow);
+    assert_eq!(calc.stack().len(), 10);
+    let err = calc.eval("defer later later").unwrap_err();
+    assert_eq!(err.kind, CalcError::UnsetDeferred("later".into()));
+}
+
+#[test]
+fn redefined_and_forgotten_words() {
+    let mut calc = Calculator::new();
+    let stack = calc.eval(": a 1 ;\n: b a 10 + ;\n: a 2 ;\nb a").unwrap();
+    assert_eq!(stack, vec![StackType::Double(11.0), StackType::Double(2.0)]);
+    calc.eval("clear marker m : c 3 ; m").unwrap();
+    assert_eq!(
+        calc.eval("c").unwrap_err().kind,
+        CalcError::UnknownWord("c".into())
+    );
+    // forget brings back the definition replaced after the word
+    assert_eq!(
+        calc.eval("clear forget b a").unwrap(),
+        vec![StackType::Double(1.0)]
+    );
+    calc.eval("forget a").unwrap();
+    assert_eq!(
+        calc.eval("a").unwrap_err().kind,
+        CalcError::UnknownWord("a".into())
+    );
+    assert_eq!(
+        calc.eval("clear : x 1 ; marker m : x 2 ; x m x").unwrap(),
+        vec![StackType::Double(2.0), StackType::Double(1.0)]
+    );
+    // with nothing between them, the replaced definition is reclaimed
+    calc.eval(": y 1 ; : y 2 ; forget y").unwrap();
+    assert_eq!(
+        calc.eval("y").unwrap_err().kind,
+        CalcError::UnknownWord("y".into())
+    );
+}
+
+#[test]
+fn caught_errors_push_the_code() {
+    let mut calc = Calculator::new();
+    assert_eq!(
+        calc.eval("{ 1 0 / } catch").unwrap(),
+        vec![StackType::Double(-10.0)]
+    );
+    assert_eq!(
+        calc.eval("clear 7 { drop drop } catch").unwrap(),
+        vec![StackType::Double(7.0), StackType::Double(-4.0)]
+    );
+    // the depth at the catch and the code
+    assert_eq!(
+        calc.eval("clear 1 2 3 { drop drop 0 0 0 0 1 0 / } catch")
+            .unwrap(),
+        vec![
+            StackType::Double(1.0),
+            StackType::Double(2.0),
+            StackType::Double(3.0),
+            StackType::Double(-10.0),
+        ]
+    );
+    assert_eq!(
+        calc.eval("clear 5 throw").unwrap_err().kind,
+        CalcError::Throw(5)
+    );
+}
+
+#[test]
+fn include_and_require_files() {
+    let dir = std::env::temp_dir().join(format!("rpncalc-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("lib/count.rpn"), "1 +\n").unwrap();
+    std::fs::write(dir.join("main.rpn"), "require lib/count.rpn\n").unwrap();
+
+    let mut calc = Calculator::new();
+    let main = dir.join("main.rpn");
+    let src = format!("0 include {0}\ninclude {0}\nrequire {0}", main.display());
+    // main.rpn is included twice, count.rpn is loaded once
+    assert_eq!(calc.eval(&src).unwrap(), vec![StackType::Double(1.0)]);
+    assert_eq!(
+        calc.eval("include no-such-file.rpn").unwrap_err().kind,
+        CalcError::FileNotFound("no-such-file.rpn".into())
+    );
+
+    // a file that failed to load is loaded again by require
+    let bad = dir.join("bad.rpn");
+    std::fs::write(&bad, "2 nosuch\n").unwrap();
+    let src = format!("clear include {}", bad.display());
+    assert_eq!(
+        calc.eval(&src).unwrap_err().kind,
+        CalcError::UnknownWord("nosuch".into())
+    );
+    std::fs::write(&bad, "2\n").unwrap();
+    let src = format!("clear require {}", bad.display());
+    assert_eq!(calc.eval(&src).unwrap(), vec![StackType::Double(2.0)]);
+
+    // the search path, after the directory of the including file
+    std::fs::write(dir.join("search.rpn"), "0 include count.rpn p\n").unwrap();
+    calc.parser().set_search_path(vec![dir.join("lib")]);
+    let src = format!("clear include {}", dir.join("search.rpn").display());
+    assert_eq!(calc.eval(&src).unwrap(), vec![StackType::Double(1.0)]);
+    let rpncalc = |path: &std::ffi::OsStr| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_rpncalc"))
+            .arg("-f")
+            .arg(dir.join("search.rpn"))
+            .env("RPNCALC_PATH", path)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap()
+    };
+    let out = rpncalc(dir.join("lib").as_os_str());
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "Result: 1.0\n");
+    let out = rpncalc("".as_ref());
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("file not found"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn vocabularies_separate_the_names() {
+    let mut calc = Calculator::new();
+    let src = ": avg + 2 / ;\nvocab dsp : avg 3 * ; end-vocab\n4 6 avg 1 dsp::avg";
+    assert_eq!(
+        calc.eval(src).unwrap(),
+        vec![StackType::Double(5.0), StackType::Double(3.0)]
+    );
+    assert_eq!(
+        calc.eval("clear using dsp 2 avg").unwrap(),
+        vec![StackType::Double(6.0)]
+    );
+}
+
+#[test]
+fn integers_are_exact_and_64_bit() {
+    let mut calc = Calculator::new();
+    let stack = calc
+        .eval("7 2 / intmode 1 40 shl 1 + 7 2 / -7 2 /")
+        .unwrap();
+    assert_eq!(
+        stack,
+        vec![
+            StackType::Double(3.5),
+            StackType::Int((1 << 40) + 1),
+            StackType::Int(3),
+            StackType::Int(-3),
+        ]
+    );
+    assert_eq!(
+        calc.eval("clear 9223372036854775807 1 +").unwrap_err().kind,
+        CalcError::IntegerOverflow
+    );
+    assert_eq!(
+        calc.eval("clear 1 63 shl 1 rol 255 popcount 1 clz 2.9 >int 2 >float")
+            .unwrap(),
+        vec![
+            StackType::Int(1),
+            StackType::Int(8),
+            StackType::Int(63),
+            StackType::Int(2),
+            StackType::Double(2.0),
+        ]
+    );
+    assert!(matches!(
+        calc.eval("0.5 1 and").unwrap_err().kind,
+        CalcError::TypeMismatch { .. }
+    ));
+}
+
+#[test]
+fn radix_literals_and_word_size() {
+    let mut calc = Calculator::new();
+    let stack = calc.eval("0x1F 0o17 0b1010_0101 -0x10").unwrap();
+    assert_eq!(
+        stack,
+        vec![
+            StackType::Int(31),
+            StackType::Int(15),
+            StackType::Int(165),
+            StackType::Int(-16)
+        ]
+    );
+    let stack = calc
+        .eval("clear 8 wordsize 0x81 1 rol 1 neg 0xF0 clz unsigned hex 0xFF >str")
+        .unwrap();
+    assert_eq!(
+        stack,
+        vec![
+            StackType::Int(3),
+            StackType::Int(-2),
+            StackType::Int(0),
+            StackType::Str("0xFF".into())
+        ]
+    );
+    // the arithmetic is not wrapped, decimal shows the value
+    assert_eq!(
+        calc.eval("clear intmode 100 100 + dup dec >str swap hex >str")
+            .unwrap(),
+        vec![StackType::Str("200".into()), StackType::Str("0xC8".into())]
+    );
+    // the shifts see the negative operand in the word size
+    assert_eq!(
+        calc.eval("clear signed 8 wordsize -1 1 shr 16 wordsize -1 4 shr hex >str")
+            .unwrap(),
+        vec![StackType::Int(127), StackType::Str("0xFFF".into())]
+    );
+    assert_eq!(
+        calc.eval("12 wordsize").unwrap_err().kind,
+        CalcError::BadWordSize(12.0)
+    );
+    assert_eq!(
+        calc.eval("0x1G").unwrap_err().kind,
+        CalcError::BadNumber("0x1G".into())
+    );
+}
+
+#[test]
+fn rationals_are_exact() {
+    let mut calc = Calculator::new();
+    let stack = calc
+        .eval("exactmode 1 3 / 3 * 1 3 / 1 6 / + 0.1 0.2 + 0.3 = 9223372036854775807 1 +")
+        .unwrap();
+    let big = BigRational::from_integer(BigInt::from(1u64 << 63));
+    assert_eq!(
+        stack,
+        vec![
+            StackType::Int(1),
+            StackType::Rational(BigRational::new(1.into(), 2.into())),
+            StackType::Double(1.0),
+            StackType::Rational(big),
+        ]
+    );
+    let stack = calc
+        .eval("clear 2 3 / >str 2 3 / >float floatmode 3.141592653589793 >frac >str")
+        .unwrap();
+    assert_eq!(
+        stack,
+        vec![
+            StackType::Str("2/3".into()),
+            StackType::Double(2.0 / 3.0),
+            StackType::Str("245850922/78256779".into()),
+        ]
+    );
+    assert_eq!(
+        calc.eval("exactmode 1.5e-9223372036854775808")
+            .unwrap_err()
+            .kind,
+        CalcError::BadNumber("1.5e-9223372036854775808".into())
+    );
+}
+
+#[test]
+fn complex_functions_and_cplxmode() {
+    let mut calc = Calculator::new();
+    let stack = calc.eval("-1 loge 1j expe 2 acosr").unwrap();
+    assert!(matches!(stack[0], StackType::Double(a) if a.is_nan()));
+    assert!(matches!(stack[2], StackType::Double(a) if a.is_nan()));
+    let StackType::Complex(e) = stack[1] else {
+        panic!("{stack:?}")
+    };
+    assert!((e.re - 1f64.cos()).abs() < 1e-15 && (e.im - 1f64.sin()).abs() < 1e-15);
+    let stack = calc.eval("clear cplxmode -1 loge -4 sqrt 4 sqrt").unwrap();
+    assert_eq!(
+        stack,
+        vec![
+            StackType::Complex(Complex::new(0.0, std::f64::consts::PI)),
+            StackType::Complex(Complex::new(0.0, 2.0)),
+            StackType::Double(2.0),
+        ]
+    );
+}
+
+#[test]
+fn polar_form() {
+    let mut calc = Calculator::new();
+    let stack = calc
+        .eval("2@0.5 c2p 3 4j conj degrees arg 2 90 p2c polar 1@60d >str")
+        .unwrap();
+    assert_eq!(
+        stack[..3],
+        [
+            StackType::Double(2.0),
+            StackType::Double(0.5),
+            StackType::Double(-53.13010235415598)
+        ]
+    );
+    let StackType::Complex(c) = stack[3] else {
+        panic!("{stack:?}")
+    };
+    assert!(c.re.abs() < 1e-15 && c.im == 2.0);
+    assert_eq!(stack[4], StackType::Str("1.0@60.0d".into()));
+    assert_eq!(
+        calc.eval("1@x").unwrap_err().kind,
+        CalcError::BadNumber("1@x".into())
+    );
+}
+
+#[test]
+fn elementary_functions() {
+    let mut calc = Calculator::new();
+    let src =
+        "2 3j 2 pow -8 cbrt -2.7 trunc -7 sign -7.5 2 fmod 3 4 hypot 1 2.5 max 3 2 min 0 asinh";
+    assert_eq!(
+        calc.eval(src).unwrap(),
+        vec![
+            StackType::Complex(Complex::new(-5.0, 12.0)),
+            StackType::Double(-2.0),
+            StackType::Double(-2.0),
+            StackType::Double(-1.0),
+            StackType::Double(-1.5),
+            StackType::Double(5.0),
+            StackType::Double(2.5),
+            StackType::Double(2.0),
+            StackType::Double(0.0),
+        ]
+    );
+    assert_eq!(
+        calc.eval("clear intmode -7 2 fmod 5 sign 1 1j sinh 2 atanh floatmode")
+            .unwrap()[..2],
+        [StackType::Int(-1), StackType::Int(1)]
+    );
+    assert_eq!(
+        calc.eval("1 0 fmod").unwrap_err().kind,
+        CalcError::DivisionByZero
+    );
+}
+
+#[test]
+fn special_functions_match_the_reference_values() {
+    // reference values from mpmath
+    let cases = [
+        ("0.5 gamma", 1.772_453_850_905_516),
+        ("4.5 gamma", 11.631_728_396_567_45),
+        ("-2.7 gamma", -0.931_082_784_838_964),
+        ("100 lgamma", 359.134_205_369_575_4),
+        ("0.5 erf", 0.520_499_877_813_046_5),
+        ("3 erfc", 2.209_049_699_858_544e-5),
+        ("2.5 j0", -0.048_383_776_468_197_99),
+        ("10 j1", 0.043_472_746_168_861_44),
+        ("5 30 jn", -0.143_240_295_512_077_1),
+        ("5 i0", 27.239_871_823_604_44),
+    ];
+    let mut calc = Calculator::new();
+    for (src, reference) in cases {
+        let stack = calc.eval(&format!("clear {src}")).unwrap();
+        let StackType::Double(res) = stack[0] else {
+            panic!("{src}: {stack:?}")
+        };
+        assert!(
+            (res - reference).abs() <= 1e-14 * reference.abs(),
+            "{src}: {res} != {reference}"
+        );
+    }
+    assert_eq!(
+        calc.eval("clear intmode 20 n! 52 5 ncr 10 3 npr 5 7 ncr floatmode")
+            .unwrap(),
+        vec![
+            StackType::Int(2_432_902_008_176_640_000),
+            StackType::Int(2_598_960),
+            StackType::Int(720),
+            StackType::Int(0),
+        ]
+    );
+    assert_eq!(
+        calc.eval("clear intmode 21 n!").unwrap_err().kind,
+        CalcError::IntegerOverflow
+    );
+    // the high orders underflow or are out of range, without a long recurrence
+    assert_eq!(
+        calc.eval("clear floatmode 1000000000 1.5 jn 1e18 -0.5 jn")
+            .unwrap(),
+        vec![StackType::Double(0.0), StackType::Double(0.0)]
+    );
+    assert_eq!(
+        calc.eval("clear 200000 300000 jn").unwrap_err().kind,
+        CalcError::OutOfRange
+    );
+}
+
+#[test]
+fn number_theory_on_integers() {
+    let mut calc = Calculator::new();
+    let src = "12 18 gcd 4 6 lcm -7 3 mod 7 -3 % 2 100 1000000007 modpow 3 7 modinv 100 nextprime";
+    assert_eq!(
+        calc.eval(src).unwrap(),
+        vec![
+            StackType::Int(6),
+            StackType::Int(12),
+            StackType::Double(2.0),
+            StackType::Double(-2.0),
+            StackType::Int(976_371_285),
+            StackType::Int(5),
+            StackType::Int(101),
+        ]
+    );
+    let src = "clear intmode 9223372036854775783 isprime 561 isprime 9223372036854775807 factor";
+    let mut factors = vec![StackType::Double(1.0), StackType::Double(0.0)];
+    factors.extend([7, 7, 73, 127, 337, 92737, 649657].map(StackType::Int));
+    assert_eq!(calc.eval(src).unwrap(), factors);
+    assert_eq!(
+        calc.eval("clear -12 factor").unwrap(),
+        [-1, 2, 2, 3].map(StackType::Int)
+    );
+    assert_eq!(
+        calc.eval("2 4 modinv").unwrap_err().kind,
+        CalcError::BadArgument
+    );
+    // the reals beyond 64 bits are not wrapped
+    for src in ["floatmode 1e19 5 gcd", "1e19 factor", "-1e19 isprime"] {
+        assert_eq!(
+            calc.eval(&format!("clear {src}")).unwrap_err().kind,
+            CalcError::OutOfRange
+        );
+    }
+    assert_eq!(
+        calc.eval("clear 1e18 10 gcd").unwrap(),
+        vec![StackType::Int(10)]
+    );
+}
+
+#[cfg(feature = "bigdecimal")]
+#[test]
+fn big_decimals_have_the_precision() {
+    let mut calc = Calculator::new();
+    let src = "30 precision 1 3 / >str 2 sqrt >str 1 expe >str 1 atanr 4 * >str 10 loge >str";
+    assert_eq!(
+        calc.eval(src).unwrap(),
+        vec![
+            StackType::Str("0.333333333333333333333333333333".into()),
+            StackType::Str("1.41421356237309504880168872421".into()),
+            StackType::Str("2.71828182845904523536028747135".into()),
+            StackType::Str("3.14159265358979323846264338328".into()),
+            StackType::Str("2.30258509299404568401799145468".into()),
+        ]
+    );
+    assert_eq!(
+        calc.eval("clear 30 sind >str 0.1 0.2 + 0.3 =").unwrap(),
+        vec![StackType::Str("0.5".into()), StackType::Double(1.0)]
+    );
+    assert_eq!(
+        calc.eval("-2 sqrt").unwrap_err().kind,
+        CalcError::BadArgument
+    );
+    assert_eq!(
+        calc.eval("1e100000000 1 +").unwrap_err().kind,
+        CalcError::OutOfRange
+    );
+    // the results are limited as the literals
+    for src in ["1e9999 dup *", "1e-9999 1e9999 /", "24000 expe"] {
+        assert_eq!(
+            calc.eval(&format!("clear {src}")).unwrap_err().kind,
+            CalcError::OutOfRange,
+            "{src}"
+        );
+    }
+    assert_eq!(
+        calc.eval("clear 40 precision 1e9999 sinr >str").unwrap(),
+        vec![StackType::Str(
+            "-0.7756218869370531937820545548145871452185".into()
+        )]
+    );
+}